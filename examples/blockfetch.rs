@@ -14,6 +14,7 @@ use cardano_ouroboros_network::{
         handshake,
     },
 };
+use futures::StreamExt;
 
 mod common;
 
@@ -34,17 +35,17 @@ async fn blockfetch() -> Result<(), Box<dyn std::error::Error>> {
     let mut blockfetch = blockfetch::builder()
         .first(
             26249860,
-            hex::decode("915386f44ad3a7fccee949c9d3fe43f5a20459c7401f990e1cc7d52c10be1fd6")?,
+            "915386f44ad3a7fccee949c9d3fe43f5a20459c7401f990e1cc7d52c10be1fd6".parse()?,
         )
         .last(
             26250057,
-            hex::decode("5fec758c8aaff4a7683c27b075dc3984d8d982839cc56470a682d1411c9f8198")?,
+            "5fec758c8aaff4a7683c27b075dc3984d8d982839cc56470a682d1411c9f8198".parse()?,
         )
-        .client(&mut connection)?;
+        .client(&connection)?;
     let mut blocks = blockfetch.run().await?;
 
-    while let Some(block) = blocks.next().await? {
-        cfg.handle_block(&block)?;
+    while let Some(block) = blocks.next().await {
+        cfg.handle_block(&block?)?;
     }
 
     Ok(())
@@ -31,6 +31,7 @@ use oura::{
 use cardano_ouroboros_network::model::Point;
 
 #[derive(Clone)]
+#[allow(dead_code)]
 pub struct Config {
     pub sdb: sled::Db,
     pub host: String,
@@ -78,10 +79,10 @@ impl Config {
     pub fn handle_block(&self, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
         let block_db = self.sdb.open_tree("blocks").unwrap();
 
-        let block = BlockWrapper::decode_fragment(&data[..])?;
+        let block = BlockWrapper::decode_fragment(data)?;
         let hash = hash_block_header(&block.1.header);
         //debug!("HASH: {}", hash);
-        block_db.insert(&hash, &*data)?;
+        block_db.insert(hash, data)?;
         self.writer.crawl(&block.1).unwrap();
         Ok(())
     }
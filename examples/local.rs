@@ -28,14 +28,14 @@ async fn local(magic: u32) -> Result<(), Box<dyn std::error::Error>> {
 
     let mut connection = Connection::unix_connect(socket_path).await?;
 
-    handshake::builder()
+    let outcome = handshake::builder()
         .client_to_node()
         .network_magic(magic)
         .client(&mut connection)?
         .negotiate()
         .await?;
 
-    info!("Ping UNIX socket success");
+    info!("Ping UNIX socket success, {:?}", outcome);
     Ok(())
 }
 
@@ -10,6 +10,12 @@
 use cardano_ouroboros_network::{
     mux::Connection,
     protocols::handshake,
+    protocols::handshake::{
+        HandshakeError,
+        Outcome,
+        RefuseReason,
+        Version,
+    },
 };
 use futures::future::join_all;
 use log::{
@@ -23,6 +29,34 @@ use std::{
 
 mod common;
 
+fn network(magic: u32) -> &'static str {
+    match magic {
+        764824073 => "mainnet",
+        1 => "preprod",
+        2 => "preview",
+        _ => "unknown",
+    }
+}
+
+// Ask the peer for its versions, which carry its network magic.
+async fn query_magic(host: &String, magic: u32) -> Result<Option<u32>, String> {
+    let mut connection = match Connection::tcp_connect(&host).await {
+        Ok(connection) => connection,
+        Err(_) => return Err("Could not connect.".to_string()),
+    };
+    let outcome = handshake::builder()
+        .node_to_node()
+        .network_magic(magic)
+        .query()
+        .client(&mut connection)?
+        .negotiate()
+        .await?;
+    Ok(match outcome {
+        Outcome::Queried(versions) => versions.iter().find_map(Version::network_magic),
+        Outcome::Accepted(_) => None,
+    })
+}
+
 async fn ping(host: &String, magic: u32) -> Result<(Duration, Duration), String> {
     info!("Pinging host {} magic {}.", host, magic);
     let mut connection = match Connection::tcp_connect(&host).await {
@@ -30,12 +64,28 @@ async fn ping(host: &String, magic: u32) -> Result<(Duration, Duration), String>
         Err(_) => return Err("Could not connect.".to_string()),
     };
     let connect_duration = connection.duration();
-    handshake::builder()
+    let result = handshake::builder()
         .node_to_node()
         .network_magic(magic)
         .client(&mut connection)?
         .negotiate()
-        .await?;
+        .await;
+    let received = match result {
+        Err(HandshakeError::NetworkMagicMismatch { received, .. }) => Some(received),
+        // Nodes refuse other networks without telling their magic.
+        Err(HandshakeError::Refused(RefuseReason::Refused(..))) => query_magic(host, magic).await?,
+        _ => None,
+    };
+    if let Some(received) = received.filter(|received| *received != magic) {
+        return Err(format!(
+            "Peer is on {} ({}), expected {} ({}).",
+            network(received),
+            received,
+            network(magic),
+            magic
+        ));
+    }
+    result?;
     let total_duration = connection.duration();
     Ok((connect_duration, total_duration))
 }
@@ -49,7 +99,7 @@ async fn main() {
     args.remove(0);
 
     /* Use configured host by default. */
-    if args.len() == 0 {
+    if args.is_empty() {
         args = vec![cfg.host.clone()];
     }
 
@@ -73,3 +123,35 @@ async fn main() {
     }))
     .await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn ping_reports_network() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        // A preprod node, refusing the handshake and answering the query.
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut connection = Connection::from_tcp_stream(stream);
+                handshake::builder()
+                    .node_to_node()
+                    .network_magic(1)
+                    .server(&mut connection)
+                    .unwrap()
+                    .negotiate()
+                    .await
+                    .ok();
+            }
+        });
+        assert!(ping(&host, 1).await.is_ok());
+        assert_eq!(
+            ping(&host, 764824073).await,
+            Err("Peer is on preprod (1), expected mainnet (764824073).".to_string())
+        );
+    }
+}
@@ -11,7 +11,10 @@ use cardano_ouroboros_network::{
     mux::Connection,
     protocols::handshake,
 };
-use log::info;
+use log::{
+    info,
+    warn,
+};
 use tokio::net::{
     TcpListener,
     TcpStream,
@@ -24,7 +27,10 @@ async fn serve() {
     let listener = TcpListener::bind("127.0.0.1:3001").await.unwrap();
     loop {
         let (socket, _addr) = listener.accept().await.unwrap();
-        handle(socket, &cfg).await.unwrap();
+        // Incompatible clients are refused.
+        if let Err(e) = handle(socket, &cfg).await {
+            warn!("Handshake failed: {}", e);
+        }
     }
 }
 
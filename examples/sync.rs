@@ -8,43 +8,42 @@
 //
 
 use cardano_ouroboros_network::{
-    mux::Connection,
-    protocols::{
-        chainsync,
-        handshake,
+    follower::{
+        self,
+        BlockEvent,
     },
+    mux::Connection,
 };
 
 use log::info;
 
 mod common;
 
-async fn chainsync() -> Result<(), Box<dyn std::error::Error>> {
+async fn sync() -> Result<(), Box<dyn std::error::Error>> {
     let cfg = common::init();
 
     let mut connection = Connection::tcp_connect(&cfg.host).await?;
 
-    handshake::builder()
-        .node_to_node()
+    let mut follower = follower::builder()
         .network_magic(cfg.magic)
-        .client(&mut connection)?
-        .negotiate()
-        .await?;
-
-    let mut chainsync = chainsync::builder().client(&mut connection);
-    chainsync
-        .find_intersect(vec![cfg.byron_mainnet, cfg.byron_testnet, cfg.byron_guild])
+        .intersect(vec![
+            cfg.byron_mainnet.clone(),
+            cfg.byron_testnet.clone(),
+            cfg.byron_guild.clone(),
+        ])
+        .start(&mut connection)
         .await?;
     loop {
-        match chainsync.request_next().await? {
-            chainsync::Reply::Forward(header, _tip) => {
+        match follower.next().await? {
+            BlockEvent::RollForward(header, block) => {
                 info!(
-                    "Block header: block={} slot={}",
+                    "Block: block={} slot={}",
                     header.block_number, header.slot_number
                 );
+                cfg.handle_block(&block)?;
             }
-            chainsync::Reply::Backward(point, _tip) => {
-                info!("Roll backward: slot={}", point.slot);
+            BlockEvent::RollBackward(point) => {
+                info!("Roll backward: {:?}", point);
             }
         }
     }
@@ -52,5 +51,5 @@ async fn chainsync() -> Result<(), Box<dyn std::error::Error>> {
 
 #[tokio::main]
 async fn main() {
-    chainsync().await.unwrap();
+    sync().await.unwrap();
 }
@@ -30,25 +30,30 @@ async fn tip() -> Result<(), Box<dyn std::error::Error>> {
         .negotiate()
         .await?;
 
-    let mut chainsync = chainsync::builder().client(&mut connection);
+    let mut chainsync = chainsync::builder().client(&connection);
     let intersect = chainsync
         .find_intersect(vec![cfg.byron_mainnet, cfg.byron_testnet, cfg.byron_guild])
         .await?;
-    match intersect {
-        chainsync::Intersect::Found(point, tip) => info!("= {:?}, {:?}", point, tip),
+    let tip = match intersect {
+        chainsync::Intersect::Found(point, tip) => {
+            info!("= {:?}, {:?}", point, tip);
+            tip
+        }
         _ => panic!(),
     };
+    // Jump to the tip and follow from there.
+    chainsync.find_intersect(vec![tip.into()]).await?;
     loop {
         match chainsync.request_next().await? {
-            chainsync::Reply::Forward(header, tip) => {
-                info!("+ {:?}, {:?}", header, tip);
-                if header.hash == tip.hash {
-                    info!("Reached tip!");
-                }
-                chainsync.find_intersect(vec![tip.into()]).await?;
-            }
+            chainsync::Reply::Forward(header, tip) => info!("+ {:?}, {:?}", header, tip),
             chainsync::Reply::Backward(slot, tip) => info!("- {:?}, {:?}", slot, tip),
         }
+        let progress = chainsync.progress();
+        if progress.at_tip {
+            info!("Reached tip at slot {:?}!", progress.slot());
+        } else {
+            info!("{:?} slots behind tip.", progress.slots_behind());
+        }
     }
 }
 
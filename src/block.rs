@@ -0,0 +1,1161 @@
+//
+// © 2022 PERLUR Group
+//
+// SPDX-License-Identifier: MPL-2.0
+//
+
+//
+// Block model for the Shelley based eras as delivered by BlockFetch. Bodies,
+// witness sets and auxiliary data keep their original encoding so that
+// transaction ids and body hashes can be recomputed.
+//
+
+mod cbor;
+
+use crate::{
+    model::{
+        BlockHeader,
+        Hash,
+        PoolId,
+    },
+    protocols::WrappedBlockHeader,
+    Error,
+};
+use serde_cbor::Value;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    ops::Range,
+};
+
+// Assets by policy id and asset name.
+pub type Multiasset<T> = BTreeMap<Hash<28>, BTreeMap<Vec<u8>, T>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Era {
+    Shelley,
+    Allegra,
+    Mary,
+    Alonzo,
+    Babbage,
+}
+
+impl TryFrom<u64> for Era {
+    type Error = Error;
+
+    fn try_from(tag: u64) -> Result<Self, Self::Error> {
+        match tag {
+            2 => Ok(Era::Shelley),
+            3 => Ok(Era::Allegra),
+            4 => Ok(Era::Mary),
+            5 => Ok(Era::Alonzo),
+            6 => Ok(Era::Babbage),
+            other => Err(format!("Unsupported block era: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub era: Era,
+    pub transaction_bodies: Vec<TransactionBody>,
+    pub witness_sets: Vec<WitnessSet>,
+    // Auxiliary data by transaction index.
+    pub auxiliary_data: BTreeMap<u32, AuxiliaryData>,
+    // Indexes of transactions with failing Plutus scripts, since Alonzo.
+    pub invalid_transactions: Vec<u32>,
+    bytes: Vec<u8>,
+    header: Range<usize>,
+    segments: Vec<Range<usize>>,
+}
+
+impl Block {
+    // Block as received, including the era tag.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn header_bytes(&self) -> &[u8] {
+        &self.bytes[self.header.clone()]
+    }
+
+    pub fn hash(&self) -> Hash<32> {
+        Hash::blake2b(self.header_bytes())
+    }
+
+    pub fn header(&self) -> Result<BlockHeader, Error> {
+        // ChainSync header eras start at Shelley with 1.
+        let era = match self.era {
+            Era::Shelley => 1,
+            Era::Allegra => 2,
+            Era::Mary => 3,
+            Era::Alonzo => 4,
+            Era::Babbage => 5,
+        };
+        WrappedBlockHeader {
+            era,
+            bytes: self.header_bytes().to_vec(),
+        }
+        .try_into()
+    }
+
+    // Encoded transaction bodies, witness sets, auxiliary data and, since
+    // Alonzo, invalid transactions.
+    pub fn segments(&self) -> impl Iterator<Item = &[u8]> {
+        self.segments.iter().map(|range| &self.bytes[range.clone()])
+    }
+
+    // Blake2b-256 over the concatenated hashes of the segments.
+    pub fn body_hash(&self) -> Hash<32> {
+        let hashes: Vec<u8> = self
+            .segments()
+            .flat_map(|segment| Hash::<32>::blake2b(segment).to_vec())
+            .collect();
+        Hash::blake2b(&hashes)
+    }
+
+    pub fn body_size(&self) -> u64 {
+        self.segments().map(|segment| segment.len() as u64).sum()
+    }
+
+    // Check that the block is the one announced by `header`.
+    pub fn verify(&self, header: &BlockHeader) -> Result<(), VerificationError> {
+        let hash = self.hash();
+        if hash != header.hash {
+            return Err(VerificationError::HeaderMismatch {
+                expected: header.hash,
+                found: hash,
+            });
+        }
+        let body_size = self.body_size();
+        if body_size != header.block_size as u64 {
+            return Err(VerificationError::BodySizeMismatch {
+                expected: header.block_size as u64,
+                found: body_size,
+            });
+        }
+        let body_hash = self.body_hash();
+        if body_hash != header.block_body_hash {
+            return Err(VerificationError::BodyHashMismatch {
+                expected: header.block_body_hash,
+                found: body_hash,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn transactions(&self) -> impl Iterator<Item = Transaction<'_>> {
+        self.transaction_bodies
+            .iter()
+            .zip(&self.witness_sets)
+            .enumerate()
+            .map(|(index, (body, witnesses))| {
+                let index = index as u32;
+                Transaction {
+                    index,
+                    body,
+                    witnesses,
+                    auxiliary_data: self.auxiliary_data.get(&index),
+                    is_valid: !self.invalid_transactions.contains(&index),
+                }
+            })
+    }
+}
+
+impl TryFrom<&[u8]> for Block {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if cbor::item(bytes, 0)?.end != bytes.len() {
+            return Err("Trailing bytes after block.".to_string());
+        }
+        let (era, block) = match cbor::array(bytes, 0)?.as_slice() {
+            [era, block] => (integer::<u64>(&decode(&bytes[era.clone()])?)?, block.start),
+            _ => return Err("Block must be an array of era and block.".to_string()),
+        };
+        let era = Era::try_from(era)?;
+        let mut items = cbor::array(bytes, block)?;
+        let expected = match era {
+            Era::Shelley | Era::Allegra | Era::Mary => 4,
+            Era::Alonzo | Era::Babbage => 5,
+        };
+        if items.len() != expected {
+            return Err(format!(
+                "Block of {} items required, found {}.",
+                expected,
+                items.len()
+            ));
+        }
+        let segments = items.split_off(1);
+        let header = items.remove(0);
+
+        let transaction_bodies = cbor::array(bytes, segments[0].start)?
+            .into_iter()
+            .map(|range| TransactionBody::try_from(&bytes[range]))
+            .collect::<Result<Vec<_>, _>>()?;
+        let witness_sets = cbor::array(bytes, segments[1].start)?
+            .into_iter()
+            .map(|range| WitnessSet::try_from(&bytes[range]))
+            .collect::<Result<Vec<_>, _>>()?;
+        if witness_sets.len() != transaction_bodies.len() {
+            return Err(format!(
+                "Found {} witness sets for {} transactions.",
+                witness_sets.len(),
+                transaction_bodies.len()
+            ));
+        }
+        let auxiliary_data = cbor::map(bytes, segments[2].start)?
+            .into_iter()
+            .map(|(index, data)| {
+                Ok((
+                    integer(&decode(&bytes[index])?)?,
+                    AuxiliaryData::try_from(&bytes[data])?,
+                ))
+            })
+            .collect::<Result<BTreeMap<_, _>, Error>>()?;
+        let invalid_transactions = match segments.get(3) {
+            Some(range) => array(&decode(&bytes[range.clone()])?)?
+                .iter()
+                .map(integer)
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+
+        Ok(Block {
+            era,
+            transaction_bodies,
+            witness_sets,
+            auxiliary_data,
+            invalid_transactions,
+            bytes: bytes.to_vec(),
+            header,
+            segments,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerificationError {
+    // Block header differs from the one received over ChainSync.
+    HeaderMismatch { expected: Hash<32>, found: Hash<32> },
+    BodySizeMismatch { expected: u64, found: u64 },
+    BodyHashMismatch { expected: Hash<32>, found: Hash<32> },
+}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerificationError::HeaderMismatch { expected, found } => {
+                write!(f, "Block hash {} expected, found {}.", expected, found)
+            }
+            VerificationError::BodySizeMismatch { expected, found } => {
+                write!(f, "Block body size {} expected, found {}.", expected, found)
+            }
+            VerificationError::BodyHashMismatch { expected, found } => {
+                write!(f, "Block body hash {} expected, found {}.", expected, found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerificationError {}
+
+impl From<VerificationError> for Error {
+    fn from(error: VerificationError) -> Error {
+        error.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transaction<'a> {
+    pub index: u32,
+    pub body: &'a TransactionBody,
+    pub witnesses: &'a WitnessSet,
+    pub auxiliary_data: Option<&'a AuxiliaryData>,
+    pub is_valid: bool,
+}
+
+impl Transaction<'_> {
+    pub fn id(&self) -> Hash<32> {
+        self.body.id()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionBody {
+    pub inputs: Vec<TransactionInput>,
+    pub outputs: Vec<TransactionOutput>,
+    pub fee: u64,
+    pub ttl: Option<u64>,
+    pub certificates: Vec<Certificate>,
+    // Withdrawn amounts by reward account.
+    pub withdrawals: BTreeMap<Vec<u8>, u64>,
+    // Protocol parameter update proposal, kept encoded.
+    pub update: Option<Vec<u8>>,
+    pub auxiliary_data_hash: Option<Hash<32>>,
+    pub validity_start: Option<u64>,
+    pub mint: Multiasset<i64>,
+    pub script_data_hash: Option<Hash<32>>,
+    pub collateral: Vec<TransactionInput>,
+    pub required_signers: Vec<Hash<28>>,
+    pub network_id: Option<u8>,
+    pub collateral_return: Option<TransactionOutput>,
+    pub total_collateral: Option<u64>,
+    pub reference_inputs: Vec<TransactionInput>,
+    bytes: Vec<u8>,
+}
+
+impl TransactionBody {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    // Transaction id is the Blake2b-256 hash of the encoded body.
+    pub fn id(&self) -> Hash<32> {
+        Hash::blake2b(&self.bytes)
+    }
+}
+
+impl TryFrom<&[u8]> for TransactionBody {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut body = TransactionBody {
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            fee: 0,
+            ttl: None,
+            certificates: Vec::new(),
+            withdrawals: BTreeMap::new(),
+            update: None,
+            auxiliary_data_hash: None,
+            validity_start: None,
+            mint: BTreeMap::new(),
+            script_data_hash: None,
+            collateral: Vec::new(),
+            required_signers: Vec::new(),
+            network_id: None,
+            collateral_return: None,
+            total_collateral: None,
+            reference_inputs: Vec::new(),
+            bytes: bytes.to_vec(),
+        };
+        for (key, range) in cbor::map(bytes, 0)? {
+            let value = decode(&bytes[range.clone()])?;
+            match integer::<u64>(&decode(&bytes[key])?)? {
+                0 => body.inputs = list(&value)?,
+                1 => body.outputs = list(&value)?,
+                2 => body.fee = integer(&value)?,
+                3 => body.ttl = Some(integer(&value)?),
+                4 => body.certificates = list(&value)?,
+                5 => {
+                    body.withdrawals = map(&value)?
+                        .iter()
+                        .map(|(account, amount)| {
+                            Ok((bytes_of(account)?.to_vec(), integer(amount)?))
+                        })
+                        .collect::<Result<_, Error>>()?
+                }
+                6 => body.update = Some(bytes[range].to_vec()),
+                7 => body.auxiliary_data_hash = Some(hash(&value)?),
+                8 => body.validity_start = Some(integer(&value)?),
+                9 => body.mint = multiasset(&value)?,
+                11 => body.script_data_hash = Some(hash(&value)?),
+                13 => body.collateral = list(&value)?,
+                14 => {
+                    body.required_signers =
+                        array(&value)?.iter().map(hash).collect::<Result<_, _>>()?
+                }
+                15 => body.network_id = Some(integer(&value)?),
+                16 => body.collateral_return = Some((&value).try_into()?),
+                17 => body.total_collateral = Some(integer(&value)?),
+                18 => body.reference_inputs = list(&value)?,
+                // Fields of later eras are ignored.
+                _ => (),
+            }
+        }
+        Ok(body)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TransactionInput {
+    pub transaction_id: Hash<32>,
+    pub index: u64,
+}
+
+impl TryFrom<&Value> for TransactionInput {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match array(value)? {
+            [transaction_id, index] => Ok(TransactionInput {
+                transaction_id: hash(transaction_id)?,
+                index: integer(index)?,
+            }),
+            other => Err(format!("Transaction input required, found {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionOutput {
+    pub address: Vec<u8>,
+    pub amount: Amount,
+    pub datum: Option<Datum>,
+    // Reference script, kept encoded.
+    pub script_ref: Option<Vec<u8>>,
+}
+
+impl TryFrom<&Value> for TransactionOutput {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match untag(value) {
+            // Legacy output with an optional datum hash.
+            Value::Array(items) => match items.as_slice() {
+                [address, amount, datum @ ..] if datum.len() <= 1 => Ok(TransactionOutput {
+                    address: bytes_of(address)?.to_vec(),
+                    amount: amount.try_into()?,
+                    datum: datum.first().map(hash).transpose()?.map(Datum::Hash),
+                    script_ref: None,
+                }),
+                other => Err(format!("Transaction output required, found {:?}", other)),
+            },
+            // Babbage output.
+            Value::Map(fields) => {
+                let field = |key: i128| fields.get(&Value::Integer(key));
+                Ok(TransactionOutput {
+                    address: bytes_of(field(0).ok_or("Output address required.")?)?.to_vec(),
+                    amount: field(1).ok_or("Output amount required.")?.try_into()?,
+                    datum: field(2).map(Datum::try_from).transpose()?,
+                    script_ref: field(3).map(bytes_of).transpose()?.map(<[u8]>::to_vec),
+                })
+            }
+            other => Err(format!("Transaction output required, found {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Datum {
+    Hash(Hash<32>),
+    // Encoded Plutus data.
+    Inline(Vec<u8>),
+}
+
+impl TryFrom<&Value> for Datum {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match array(value)? {
+            [kind, datum] => match integer(kind)? {
+                0 => Ok(Datum::Hash(hash(datum)?)),
+                1 => Ok(Datum::Inline(bytes_of(datum)?.to_vec())),
+                other => Err(format!("Unknown datum kind: {}", other)),
+            },
+            other => Err(format!("Datum required, found {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Amount {
+    pub coin: u64,
+    pub assets: Multiasset<u64>,
+}
+
+impl TryFrom<&Value> for Amount {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match untag(value) {
+            Value::Array(items) => match items.as_slice() {
+                [coin, assets] => Ok(Amount {
+                    coin: integer(coin)?,
+                    assets: multiasset(assets)?,
+                }),
+                other => Err(format!("Amount required, found {:?}", other)),
+            },
+            coin => Ok(Amount {
+                coin: integer(coin)?,
+                assets: BTreeMap::new(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StakeCredential {
+    KeyHash(Hash<28>),
+    ScriptHash(Hash<28>),
+}
+
+impl TryFrom<&Value> for StakeCredential {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match array(value)? {
+            [kind, credential] => match integer(kind)? {
+                0 => Ok(StakeCredential::KeyHash(hash(credential)?)),
+                1 => Ok(StakeCredential::ScriptHash(hash(credential)?)),
+                other => Err(format!("Unknown stake credential kind: {}", other)),
+            },
+            other => Err(format!("Stake credential required, found {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Certificate {
+    StakeRegistration(StakeCredential),
+    StakeDeregistration(StakeCredential),
+    StakeDelegation(StakeCredential, PoolId),
+    PoolRegistration(PoolParams),
+    // Pool id and retirement epoch.
+    PoolRetirement(PoolId, u64),
+    GenesisKeyDelegation {
+        genesis_hash: Hash<28>,
+        delegate_hash: Hash<28>,
+        vrf_keyhash: Hash<32>,
+    },
+    MoveInstantaneousRewards(MoveInstantaneousRewards),
+}
+
+impl TryFrom<&Value> for Certificate {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        let items = array(value)?;
+        let kind = integer::<u64>(items.first().ok_or("Empty certificate.")?)?;
+        Ok(match (kind, &items[1..]) {
+            (0, [credential]) => Certificate::StakeRegistration(credential.try_into()?),
+            (1, [credential]) => Certificate::StakeDeregistration(credential.try_into()?),
+            (2, [credential, pool]) => {
+                Certificate::StakeDelegation(credential.try_into()?, hash(pool)?)
+            }
+            (3, params) => Certificate::PoolRegistration(params.try_into()?),
+            (4, [pool, epoch]) => Certificate::PoolRetirement(hash(pool)?, integer(epoch)?),
+            (5, [genesis_hash, delegate_hash, vrf_keyhash]) => Certificate::GenesisKeyDelegation {
+                genesis_hash: hash(genesis_hash)?,
+                delegate_hash: hash(delegate_hash)?,
+                vrf_keyhash: hash(vrf_keyhash)?,
+            },
+            (6, [rewards]) => Certificate::MoveInstantaneousRewards(rewards.try_into()?),
+            (kind, _) => return Err(format!("Bad certificate of kind {}.", kind)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolParams {
+    pub operator: PoolId,
+    pub vrf_keyhash: Hash<32>,
+    pub pledge: u64,
+    pub cost: u64,
+    // Numerator and denominator.
+    pub margin: (u64, u64),
+    pub reward_account: Vec<u8>,
+    pub owners: Vec<Hash<28>>,
+    pub relays: Vec<Relay>,
+    pub metadata: Option<PoolMetadata>,
+}
+
+impl TryFrom<&[Value]> for PoolParams {
+    type Error = Error;
+
+    fn try_from(items: &[Value]) -> Result<Self, Self::Error> {
+        match items {
+            [operator, vrf_keyhash, pledge, cost, margin, reward_account, owners, relays, metadata] => {
+                Ok(PoolParams {
+                    operator: hash(operator)?,
+                    vrf_keyhash: hash(vrf_keyhash)?,
+                    pledge: integer(pledge)?,
+                    cost: integer(cost)?,
+                    margin: match array(margin)? {
+                        [numerator, denominator] => (integer(numerator)?, integer(denominator)?),
+                        other => return Err(format!("Unit interval required, found {:?}", other)),
+                    },
+                    reward_account: bytes_of(reward_account)?.to_vec(),
+                    owners: array(owners)?.iter().map(hash).collect::<Result<_, _>>()?,
+                    relays: list(relays)?,
+                    metadata: optional(metadata).map(PoolMetadata::try_from).transpose()?,
+                })
+            }
+            other => Err(format!("Pool parameters required, found {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Relay {
+    // IP addresses are kept in their wire format.
+    SingleHostAddr {
+        port: Option<u16>,
+        ipv4: Option<Vec<u8>>,
+        ipv6: Option<Vec<u8>>,
+    },
+    SingleHostName {
+        port: Option<u16>,
+        dns_name: String,
+    },
+    MultiHostName {
+        dns_name: String,
+    },
+}
+
+impl TryFrom<&Value> for Relay {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        let items = array(value)?;
+        let kind = integer::<u64>(items.first().ok_or("Empty relay.")?)?;
+        let port = |port| optional(port).map(integer).transpose();
+        Ok(match (kind, &items[1..]) {
+            (0, [port_value, ipv4, ipv6]) => Relay::SingleHostAddr {
+                port: port(port_value)?,
+                ipv4: optional(ipv4)
+                    .map(bytes_of)
+                    .transpose()?
+                    .map(<[u8]>::to_vec),
+                ipv6: optional(ipv6)
+                    .map(bytes_of)
+                    .transpose()?
+                    .map(<[u8]>::to_vec),
+            },
+            (1, [port_value, dns_name]) => Relay::SingleHostName {
+                port: port(port_value)?,
+                dns_name: text(dns_name)?.to_string(),
+            },
+            (2, [dns_name]) => Relay::MultiHostName {
+                dns_name: text(dns_name)?.to_string(),
+            },
+            (kind, _) => return Err(format!("Bad relay of kind {}.", kind)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolMetadata {
+    pub url: String,
+    pub hash: Hash<32>,
+}
+
+impl TryFrom<&Value> for PoolMetadata {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match array(value)? {
+            [url, metadata_hash] => Ok(PoolMetadata {
+                url: text(url)?.to_string(),
+                hash: hash(metadata_hash)?,
+            }),
+            other => Err(format!("Pool metadata required, found {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pot {
+    Reserves,
+    Treasury,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MirTarget {
+    StakeCredentials(BTreeMap<StakeCredential, i64>),
+    // Amount transferred to the other pot.
+    OtherPot(u64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoveInstantaneousRewards {
+    pub source: Pot,
+    pub target: MirTarget,
+}
+
+impl TryFrom<&Value> for MoveInstantaneousRewards {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        let (source, target) = match array(value)? {
+            [source, target] => (source, target),
+            other => return Err(format!("Instantaneous rewards required, found {:?}", other)),
+        };
+        Ok(MoveInstantaneousRewards {
+            source: match integer(source)? {
+                0 => Pot::Reserves,
+                1 => Pot::Treasury,
+                other => return Err(format!("Unknown pot: {}", other)),
+            },
+            target: match untag(target) {
+                Value::Map(rewards) => MirTarget::StakeCredentials(
+                    rewards
+                        .iter()
+                        .map(|(credential, amount)| Ok((credential.try_into()?, integer(amount)?)))
+                        .collect::<Result<_, Error>>()?,
+                ),
+                coin => MirTarget::OtherPot(integer(coin)?),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WitnessSet {
+    pub vkey_witnesses: Vec<VKeyWitness>,
+    pub bootstrap_witnesses: Vec<BootstrapWitness>,
+    // Scripts, Plutus data and redeemers are kept encoded.
+    pub native_scripts: Vec<Vec<u8>>,
+    pub plutus_v1_scripts: Vec<Vec<u8>>,
+    pub plutus_v2_scripts: Vec<Vec<u8>>,
+    pub plutus_data: Vec<Vec<u8>>,
+    pub redeemers: Vec<Vec<u8>>,
+    bytes: Vec<u8>,
+}
+
+impl WitnessSet {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl TryFrom<&[u8]> for WitnessSet {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut witnesses = WitnessSet {
+            vkey_witnesses: Vec::new(),
+            bootstrap_witnesses: Vec::new(),
+            native_scripts: Vec::new(),
+            plutus_v1_scripts: Vec::new(),
+            plutus_v2_scripts: Vec::new(),
+            plutus_data: Vec::new(),
+            redeemers: Vec::new(),
+            bytes: bytes.to_vec(),
+        };
+        for (key, range) in cbor::map(bytes, 0)? {
+            let encoded = || encoded_items(bytes, &range);
+            match integer::<u64>(&decode(&bytes[key])?)? {
+                0 => witnesses.vkey_witnesses = list(&decode(&bytes[range.clone()])?)?,
+                1 => witnesses.native_scripts = encoded()?,
+                2 => witnesses.bootstrap_witnesses = list(&decode(&bytes[range.clone()])?)?,
+                3 => witnesses.plutus_v1_scripts = encoded()?,
+                4 => witnesses.plutus_data = encoded()?,
+                5 => witnesses.redeemers = encoded()?,
+                6 => witnesses.plutus_v2_scripts = encoded()?,
+                _ => (),
+            }
+        }
+        Ok(witnesses)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VKeyWitness {
+    pub vkey: Hash<32>,
+    pub signature: Vec<u8>,
+}
+
+impl TryFrom<&Value> for VKeyWitness {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match array(value)? {
+            [vkey, signature] => Ok(VKeyWitness {
+                vkey: hash(vkey)?,
+                signature: bytes_of(signature)?.to_vec(),
+            }),
+            other => Err(format!("Vkey witness required, found {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootstrapWitness {
+    pub public_key: Hash<32>,
+    pub signature: Vec<u8>,
+    pub chain_code: Hash<32>,
+    pub attributes: Vec<u8>,
+}
+
+impl TryFrom<&Value> for BootstrapWitness {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match array(value)? {
+            [public_key, signature, chain_code, attributes] => Ok(BootstrapWitness {
+                public_key: hash(public_key)?,
+                signature: bytes_of(signature)?.to_vec(),
+                chain_code: hash(chain_code)?,
+                attributes: bytes_of(attributes)?.to_vec(),
+            }),
+            other => Err(format!("Bootstrap witness required, found {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuxiliaryData {
+    pub metadata: BTreeMap<u64, Metadatum>,
+    // Scripts are kept encoded.
+    pub native_scripts: Vec<Vec<u8>>,
+    pub plutus_v1_scripts: Vec<Vec<u8>>,
+    pub plutus_v2_scripts: Vec<Vec<u8>>,
+    bytes: Vec<u8>,
+}
+
+impl AuxiliaryData {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    // Hash referenced by `TransactionBody::auxiliary_data_hash`.
+    pub fn hash(&self) -> Hash<32> {
+        Hash::blake2b(&self.bytes)
+    }
+}
+
+impl TryFrom<&[u8]> for AuxiliaryData {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut data = AuxiliaryData {
+            metadata: BTreeMap::new(),
+            native_scripts: Vec::new(),
+            plutus_v1_scripts: Vec::new(),
+            plutus_v2_scripts: Vec::new(),
+            bytes: bytes.to_vec(),
+        };
+        let metadata = |range: &Range<usize>| -> Result<BTreeMap<u64, Metadatum>, Error> {
+            map(&decode(&bytes[range.clone()])?)?
+                .iter()
+                .map(|(label, value)| Ok((integer(label)?, value.try_into()?)))
+                .collect()
+        };
+        match cbor::tag(bytes, 0)? {
+            // Alonzo map of metadata and scripts.
+            Some((259, _)) => {
+                for (key, range) in cbor::map(bytes, 0)? {
+                    match integer::<u64>(&decode(&bytes[key])?)? {
+                        0 => data.metadata = metadata(&range)?,
+                        1 => data.native_scripts = encoded_items(bytes, &range)?,
+                        2 => data.plutus_v1_scripts = encoded_items(bytes, &range)?,
+                        3 => data.plutus_v2_scripts = encoded_items(bytes, &range)?,
+                        _ => (),
+                    }
+                }
+            }
+            Some((tag, _)) => return Err(format!("Unknown auxiliary data tag: {}", tag)),
+            None => match cbor::array(bytes, 0) {
+                // Allegra array of metadata and native scripts.
+                Ok(items) => match items.as_slice() {
+                    [metadata_range, scripts] => {
+                        data.metadata = metadata(metadata_range)?;
+                        data.native_scripts = encoded_items(bytes, scripts)?;
+                    }
+                    other => return Err(format!("Bad auxiliary data of {} items.", other.len())),
+                },
+                // Shelley metadata map.
+                Err(_) => data.metadata = metadata(&(0..bytes.len()))?,
+            },
+        }
+        Ok(data)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Metadatum {
+    Int(i128),
+    Bytes(Vec<u8>),
+    Text(String),
+    List(Vec<Metadatum>),
+    Map(Vec<(Metadatum, Metadatum)>),
+}
+
+impl TryFrom<&Value> for Metadatum {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        Ok(match untag(value) {
+            Value::Integer(value) => Metadatum::Int(*value),
+            Value::Bytes(bytes) => Metadatum::Bytes(bytes.clone()),
+            Value::Text(text) => Metadatum::Text(text.clone()),
+            Value::Array(items) => Metadatum::List(
+                items
+                    .iter()
+                    .map(Metadatum::try_from)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Map(entries) => Metadatum::Map(
+                entries
+                    .iter()
+                    .map(|(key, value)| Ok((key.try_into()?, value.try_into()?)))
+                    .collect::<Result<_, Error>>()?,
+            ),
+            other => return Err(format!("Metadatum required, found {:?}", other)),
+        })
+    }
+}
+
+//
+// Helpers decoding `serde_cbor` values, tags are ignored.
+//
+
+fn decode(bytes: &[u8]) -> Result<Value, Error> {
+    serde_cbor::from_slice(bytes).map_err(|e| format!("{:?}", e))
+}
+
+fn untag(value: &Value) -> &Value {
+    match value {
+        Value::Tag(_, value) => untag(value),
+        value => value,
+    }
+}
+
+fn integer<T: TryFrom<i128>>(value: &Value) -> Result<T, Error> {
+    match untag(value) {
+        &Value::Integer(integer) => {
+            T::try_from(integer).map_err(|_| format!("Integer out of range: {}", integer))
+        }
+        other => Err(format!("Integer required, found {:?}", other)),
+    }
+}
+
+fn bytes_of(value: &Value) -> Result<&[u8], Error> {
+    match untag(value) {
+        Value::Bytes(bytes) => Ok(bytes),
+        other => Err(format!("Bytes required, found {:?}", other)),
+    }
+}
+
+fn hash<const N: usize>(value: &Value) -> Result<Hash<N>, Error> {
+    bytes_of(value)?.try_into()
+}
+
+fn text(value: &Value) -> Result<&str, Error> {
+    match untag(value) {
+        Value::Text(text) => Ok(text),
+        other => Err(format!("Text required, found {:?}", other)),
+    }
+}
+
+fn array(value: &Value) -> Result<&[Value], Error> {
+    match untag(value) {
+        Value::Array(items) => Ok(items),
+        other => Err(format!("Array required, found {:?}", other)),
+    }
+}
+
+fn map(value: &Value) -> Result<&BTreeMap<Value, Value>, Error> {
+    match untag(value) {
+        Value::Map(entries) => Ok(entries),
+        other => Err(format!("Map required, found {:?}", other)),
+    }
+}
+
+fn optional(value: &Value) -> Option<&Value> {
+    match untag(value) {
+        Value::Null => None,
+        value => Some(value),
+    }
+}
+
+fn list<'a, T: TryFrom<&'a Value, Error = Error>>(value: &'a Value) -> Result<Vec<T>, Error> {
+    array(value)?.iter().map(T::try_from).collect()
+}
+
+fn multiasset<T: TryFrom<i128>>(value: &Value) -> Result<Multiasset<T>, Error> {
+    map(value)?
+        .iter()
+        .map(|(policy, assets)| {
+            let assets = map(assets)?
+                .iter()
+                .map(|(name, quantity)| Ok((bytes_of(name)?.to_vec(), integer(quantity)?)))
+                .collect::<Result<_, Error>>()?;
+            Ok((hash(policy)?, assets))
+        })
+        .collect()
+}
+
+// Original encoding of the items of the array at `range`.
+fn encoded_items(bytes: &[u8], range: &Range<usize>) -> Result<Vec<Vec<u8>>, Error> {
+    Ok(cbor::array(&bytes[..range.end], range.start)?
+        .into_iter()
+        .map(|item| bytes[item].to_vec())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::blockfetch;
+
+    fn block(name: &str) -> Block {
+        let data = match name {
+            "shelley" => include_str!("block/test_data/shelley.block"),
+            "allegra" => include_str!("block/test_data/allegra.block"),
+            "mary" => include_str!("block/test_data/mary.block"),
+            "alonzo" => include_str!("block/test_data/alonzo.block"),
+            "babbage" => include_str!("block/test_data/babbage.block"),
+            other => panic!("No {} block.", other),
+        };
+        Block::try_from(hex::decode(data.trim()).unwrap().as_slice()).unwrap()
+    }
+
+    #[test]
+    fn block_decodes() {
+        for (name, era, header_era, count) in [
+            ("shelley", Era::Shelley, 1, 0),
+            ("allegra", Era::Allegra, 2, 10),
+            ("mary", Era::Mary, 3, 20),
+            ("alonzo", Era::Alonzo, 4, 5),
+            ("babbage", Era::Babbage, 5, 2),
+        ] {
+            let block = block(name);
+            assert_eq!(block.era, era);
+            assert_eq!(block.transactions().count(), count);
+            assert_eq!(block.segments().count(), 3 + (era >= Era::Alonzo) as usize);
+            let header = block.header().unwrap();
+            assert_eq!(header.hash, block.hash());
+            assert_eq!(blockfetch::block_hash(&block.bytes), Ok(block.hash()));
+            assert_eq!(header.era, header_era);
+            assert_eq!(header.bytes, block.header_bytes());
+            for transaction in block.transactions() {
+                assert!(!transaction.body.inputs.is_empty());
+                assert!(!transaction.body.outputs.is_empty());
+                assert_eq!(
+                    transaction.body.auxiliary_data_hash,
+                    transaction.auxiliary_data.map(AuxiliaryData::hash),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn verify_works() {
+        for name in ["shelley", "allegra", "mary", "alonzo", "babbage"] {
+            let block = block(name);
+            let header = block.header().unwrap();
+            assert_eq!(block.verify(&header), Ok(()));
+
+            let mut bad = header.clone();
+            bad.hash = Hash::new([0; 32]);
+            assert!(matches!(
+                block.verify(&bad),
+                Err(VerificationError::HeaderMismatch { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn verify_detects_forged_body() {
+        let block = block("alonzo");
+        let header = block.header().unwrap();
+
+        // Flip a byte of the first transaction id spent in the block.
+        let mut bytes = block.bytes().to_vec();
+        let body = block.segments[0].start;
+        let offset = bytes[body..]
+            .windows(2)
+            .position(|window| window == [0x58, 0x20])
+            .unwrap();
+        bytes[body + offset + 2] ^= 1;
+        let forged = Block::try_from(bytes.as_slice()).unwrap();
+        assert_ne!(forged.transaction_bodies[0], block.transaction_bodies[0]);
+        assert!(matches!(
+            forged.verify(&header),
+            Err(VerificationError::BodyHashMismatch { .. })
+        ));
+
+        let mut bad = header;
+        bad.block_size += 1;
+        assert_eq!(
+            block.verify(&bad),
+            Err(VerificationError::BodySizeMismatch {
+                expected: block.body_size() + 1,
+                found: block.body_size(),
+            })
+        );
+    }
+
+    #[test]
+    fn transaction_ids_match() {
+        let ids: Vec<String> = block("alonzo")
+            .transactions()
+            .map(|transaction| transaction.id().to_string())
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                "8ae0cd531635579a9b52b954a840782d12235251fb1451e5c699e864c677514a",
+                "bb5bb4e1c09c02aa199c60e9f330102912e3ef977bb73ecfd8f790945c6091d4",
+                "8cdd88042ddb6c800714fb1469fb1a1a93152aae3c87a81f2a3016f2ee5c664a",
+                "10add6bdaa7ade06466bdd768456e756709090846b58bf473f240c484db517fa",
+                "8838f5ab27894a6543255aeaec086f7b3405a6db6e7457a541409cdbbf0cd474",
+            ]
+        );
+    }
+
+    #[test]
+    fn babbage_block_decodes() {
+        let block = block("babbage");
+        let header = block.header().unwrap();
+        assert_eq!(header.era, 5);
+        assert_eq!(header.protocol_major_version, 7);
+        assert_eq!(header.operational_cert.kes_period, 558);
+        // The single Praos VRF result fills both VRF fields.
+        assert_eq!(header.eta_vrf_0, header.leader_vrf_0);
+        assert_eq!(header.leader_vrf_1.len(), 80);
+
+        let transaction = block.transactions().next().unwrap();
+        let body = transaction.body;
+        assert!(matches!(body.outputs[0].datum, Some(Datum::Inline(_))));
+        assert!(body.outputs[0].script_ref.is_some());
+        assert_eq!(body.outputs[1].datum, None);
+        assert_eq!(
+            body.collateral_return.as_ref().unwrap().amount.coin,
+            4653187
+        );
+        assert_eq!(body.total_collateral, Some(346781));
+        assert_eq!(body.reference_inputs.len(), 1);
+        assert_eq!(transaction.witnesses.plutus_v2_scripts.len(), 1);
+        assert_eq!(transaction.witnesses.redeemers.len(), 1);
+    }
+
+    #[test]
+    fn babbage_output_decodes() {
+        // {0: h'01', 1: [2, {h'03'*28: {h'04': 5}}], 2: [1, 24(h'06')], 3: 24(h'07')}
+        let bytes = hex::decode(format!(
+            "a4004101018202a1581c{}a1410405028201d818410603d8184107",
+            "03".repeat(28)
+        ))
+        .unwrap();
+        let output = TransactionOutput::try_from(&decode(&bytes).unwrap()).unwrap();
+        assert_eq!(
+            output,
+            TransactionOutput {
+                address: vec![1],
+                amount: Amount {
+                    coin: 2,
+                    assets: [(Hash::new([3; 28]), [(vec![4], 5)].into())].into(),
+                },
+                datum: Some(Datum::Inline(vec![6])),
+                script_ref: Some(vec![7]),
+            }
+        );
+    }
+
+    #[test]
+    fn block_rejects_garbage() {
+        let bytes = hex::decode(include_str!("block/test_data/shelley.block").trim()).unwrap();
+        assert!(Block::try_from(&bytes[..bytes.len() - 1]).is_err());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(Block::try_from(trailing.as_slice()).is_err());
+        // Byron blocks are not supported.
+        let mut byron = bytes;
+        byron[1] = 0x01;
+        assert!(Block::try_from(byron.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+//
+// © 2022 PERLUR Group
+//
+// SPDX-License-Identifier: MPL-2.0
+//
+
+//
+// Minimal CBOR scanner locating items in the original bytes, so that
+// hashes can be computed over exactly what the peer sent.
+//
+
+use crate::Error;
+use std::ops::Range;
+
+const MAX_DEPTH: usize = 256;
+const BREAK: u8 = 0xff;
+
+// Key and value ranges of a map entry.
+pub(crate) type Entry = (Range<usize>, Range<usize>);
+
+struct Head {
+    major: u8,
+    // `None` for indefinite length items.
+    argument: Option<u64>,
+    length: usize,
+}
+
+fn head(bytes: &[u8], offset: usize) -> Result<Head, Error> {
+    let initial = *bytes.get(offset).ok_or("Unexpected end of CBOR.")?;
+    let major = initial >> 5;
+    let (argument, length) = match initial & 0x1f {
+        info @ 0..=23 => (Some(info as u64), 1),
+        info @ 24..=27 => {
+            let size = 1 << (info - 24);
+            let data = bytes
+                .get(offset + 1..offset + 1 + size)
+                .ok_or("Unexpected end of CBOR.")?;
+            let argument = data.iter().fold(0u64, |acc, byte| acc << 8 | *byte as u64);
+            (Some(argument), 1 + size)
+        }
+        31 if (2..=5).contains(&major) => (None, 1),
+        other => return Err(format!("Unsupported CBOR additional info: {}", other)),
+    };
+    Ok(Head {
+        major,
+        argument,
+        length,
+    })
+}
+
+fn item_end_at(bytes: &[u8], offset: usize, depth: usize) -> Result<usize, Error> {
+    if depth > MAX_DEPTH {
+        return Err("CBOR nested too deep.".to_string());
+    }
+    let head = head(bytes, offset)?;
+    let mut position = offset + head.length;
+    match (head.major, head.argument) {
+        (0 | 1 | 7, _) => (),
+        (2 | 3, Some(length)) => {
+            position = usize::try_from(length)
+                .ok()
+                .and_then(|length| position.checked_add(length))
+                .ok_or("CBOR string too long.")?;
+        }
+        (4 | 5, Some(count)) => {
+            let items = match head.major {
+                4 => count,
+                _ => count.saturating_mul(2),
+            };
+            for _ in 0..items {
+                position = item_end_at(bytes, position, depth + 1)?;
+            }
+        }
+        (6, Some(_)) => position = item_end_at(bytes, position, depth + 1)?,
+        (_, None) => {
+            while *bytes.get(position).ok_or("Unexpected end of CBOR.")? != BREAK {
+                position = item_end_at(bytes, position, depth + 1)?;
+            }
+            position += 1;
+        }
+        (major, _) => return Err(format!("Unsupported CBOR major type: {}", major)),
+    }
+    match position <= bytes.len() {
+        true => Ok(position),
+        false => Err("Unexpected end of CBOR.".to_string()),
+    }
+}
+
+pub(crate) fn item(bytes: &[u8], offset: usize) -> Result<Range<usize>, Error> {
+    Ok(offset..item_end_at(bytes, offset, 0)?)
+}
+
+// Tag number and the offset of the tagged item, if the item is tagged.
+pub(crate) fn tag(bytes: &[u8], offset: usize) -> Result<Option<(u64, usize)>, Error> {
+    let head = head(bytes, offset)?;
+    Ok(match (head.major, head.argument) {
+        (6, Some(tag)) => Some((tag, offset + head.length)),
+        _ => None,
+    })
+}
+
+fn untagged(bytes: &[u8], mut offset: usize) -> Result<usize, Error> {
+    while let Some((_, inner)) = tag(bytes, offset)? {
+        offset = inner;
+    }
+    Ok(offset)
+}
+
+fn items(bytes: &[u8], offset: usize, major: u8) -> Result<Vec<Range<usize>>, Error> {
+    let offset = untagged(bytes, offset)?;
+    let head = head(bytes, offset)?;
+    if head.major != major {
+        return Err(format!(
+            "CBOR major type {} required, found {}.",
+            major, head.major
+        ));
+    }
+    // Maps hold a key and a value per entry.
+    let count = match head.argument {
+        Some(count) => Some(
+            count
+                .checked_mul(major as u64 - 3)
+                .ok_or("CBOR item count too large.")?,
+        ),
+        None => None,
+    };
+    let mut position = offset + head.length;
+    let mut items = Vec::new();
+    loop {
+        match count {
+            Some(count) if items.len() as u64 == count => break,
+            None if *bytes.get(position).ok_or("Unexpected end of CBOR.")? == BREAK => break,
+            _ => {
+                let item = item(bytes, position)?;
+                position = item.end;
+                items.push(item);
+            }
+        }
+    }
+    Ok(items)
+}
+
+pub(crate) fn array(bytes: &[u8], offset: usize) -> Result<Vec<Range<usize>>, Error> {
+    items(bytes, offset, 4)
+}
+
+pub(crate) fn map(bytes: &[u8], offset: usize) -> Result<Vec<Entry>, Error> {
+    let mut items = items(bytes, offset, 5)?.into_iter();
+    let mut entries = Vec::new();
+    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+        entries.push((key, value));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scanner_works() {
+        // [1, [2, 3], h'abcd', {4: "x"}, 24(h'00')]
+        let bytes = hex::decode("850182020342abcda1046178d8184100").unwrap();
+        let items = array(&bytes, 0).unwrap();
+        assert_eq!(items, vec![1..2, 2..5, 5..8, 8..12, 12..16]);
+        assert_eq!(array(&bytes, items[1].start).unwrap(), vec![3..4, 4..5]);
+        assert_eq!(map(&bytes, items[3].start).unwrap(), vec![(9..10, 10..12)]);
+        assert_eq!(tag(&bytes, items[4].start).unwrap(), Some((24, 14)));
+        assert!(array(&bytes, items[2].start).is_err());
+        assert!(item(&bytes[..10], 0).is_err());
+    }
+
+    #[test]
+    fn scanner_handles_indefinite_length() {
+        // [_ 1, [_ ], {_ 2: 3}]
+        let bytes = hex::decode("9f019fffbf0203ffff").unwrap();
+        assert_eq!(item(&bytes, 0).unwrap(), 0..9);
+        let items = array(&bytes, 0).unwrap();
+        assert_eq!(items, vec![1..2, 2..4, 4..8]);
+        assert!(array(&bytes, items[1].start).unwrap().is_empty());
+        assert_eq!(map(&bytes, items[2].start).unwrap(), vec![(5..6, 6..7)]);
+    }
+
+    #[test]
+    fn scanner_rejects_huge_counts() {
+        for bytes in [
+            "bbffffffffffffffff",
+            "bb800000000000000001",
+            "9bffffffffffffffff",
+        ] {
+            let bytes = hex::decode(bytes).unwrap();
+            assert!(map(&bytes, 0).is_err());
+            assert!(array(&bytes, 0).is_err());
+            assert!(item(&bytes, 0).is_err());
+        }
+    }
+}
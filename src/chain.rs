@@ -0,0 +1,382 @@
+//
+// © 2022 PERLUR Group
+//
+// SPDX-License-Identifier: MPL-2.0
+//
+
+use crate::{
+    model::{
+        BlockHeader,
+        Point,
+    },
+    protocols::chainsync::Reply,
+    Error,
+};
+use std::{
+    collections::VecDeque,
+    fs,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+// Mainnet security parameter.
+pub const SECURITY_PARAM: u64 = 2160;
+
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+pub enum FragmentEvent {
+    // Header is deeper than k blocks and cannot be rolled back anymore.
+    Immutable(BlockHeader),
+    // Points removed by a rollback, newest first.
+    RolledBack(Vec<Point>),
+}
+
+//
+// Volatile part of the chain, i.e. the last k headers on top of an anchor
+// point that is already immutable. Other items can be tracked per point,
+// e.g. fetched blocks or block numbers.
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainFragment<T = BlockHeader> {
+    k: u64,
+    anchor: Point,
+    items: VecDeque<(Point, T)>,
+}
+
+impl ChainFragment {
+    pub fn new(anchor: Point) -> Self {
+        ChainFragment::with_security_param(anchor, SECURITY_PARAM)
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = &BlockHeader> {
+        self.items()
+    }
+
+    pub fn apply(&mut self, reply: &Reply) -> Result<Vec<FragmentEvent>, Error> {
+        match reply {
+            Reply::Forward(header, _) => Ok(self.roll_forward(header.clone())),
+            Reply::Backward(point, _) => Ok(self.roll_backward(point)?.into_iter().collect()),
+        }
+    }
+
+    pub fn roll_forward(&mut self, header: BlockHeader) -> Vec<FragmentEvent> {
+        self.push(header.point(), header)
+            .into_iter()
+            .map(|(_, header)| FragmentEvent::Immutable(header))
+            .collect()
+    }
+
+    // Rollback to the anchor or one of the volatile headers.
+    pub fn roll_backward(&mut self, point: &Point) -> Result<Option<FragmentEvent>, Error> {
+        let points: Vec<Point> = self
+            .truncate(point)?
+            .into_iter()
+            .map(|(point, _)| point)
+            .collect();
+        Ok(match points.is_empty() {
+            true => None,
+            false => Some(FragmentEvent::RolledBack(points)),
+        })
+    }
+}
+
+impl<T> ChainFragment<T> {
+    pub fn with_security_param(anchor: Point, k: u64) -> Self {
+        ChainFragment {
+            k,
+            anchor,
+            items: VecDeque::new(),
+        }
+    }
+
+    // Drop all items and continue from `anchor`, e.g. an intersection.
+    pub fn reset(&mut self, anchor: Point) {
+        self.anchor = anchor;
+        self.items.clear();
+    }
+
+    pub fn anchor(&self) -> &Point {
+        &self.anchor
+    }
+
+    pub fn tip(&self) -> Point {
+        match self.items.back() {
+            Some((point, _)) => point.clone(),
+            None => self.anchor.clone(),
+        }
+    }
+
+    pub fn items(&self) -> impl DoubleEndedIterator<Item = &T> {
+        self.items.iter().map(|(_, item)| item)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    // Append `item` and return the items that became immutable, oldest first.
+    pub fn push(&mut self, point: Point, item: T) -> Vec<(Point, T)> {
+        self.items.push_back((point, item));
+        let mut immutable = Vec::new();
+        while self.items.len() as u64 > self.k {
+            let (point, item) = self.items.pop_front().unwrap();
+            self.anchor = point.clone();
+            immutable.push((point, item));
+        }
+        immutable
+    }
+
+    // Drop the items after `point` and return them, newest first.
+    pub fn truncate(&mut self, point: &Point) -> Result<Vec<(Point, T)>, Error> {
+        let keep = match *point == self.anchor {
+            true => 0,
+            false => {
+                self.items
+                    .iter()
+                    .position(|(known, _)| known == point)
+                    .ok_or_else(|| format!("Rollback point {:?} not found.", point))?
+                    + 1
+            }
+        };
+        Ok(self.items.drain(keep..).rev().collect())
+    }
+
+    // Tip followed by exponentially spaced points and the anchor, to be
+    // passed to `find_intersect` on reconnect.
+    pub fn intersect_points(&self) -> Vec<Point> {
+        let mut points: Vec<Point> = exponential_offsets(self.items.len())
+            .into_iter()
+            .map(|offset| self.items[self.items.len() - 1 - offset].0.clone())
+            .collect();
+        points.push(self.anchor.clone());
+        points
+    }
+}
+
+// Offsets 0, 1, 3, 7, 15, ... below `len`.
+pub(crate) fn exponential_offsets(len: usize) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        offsets.push(offset);
+        offset = offset * 2 + 1;
+    }
+    offsets
+}
+
+//
+// Persistent storage of recently processed points, so that a restarted
+// client can resume with `ChainSync::resume`.
+//
+pub trait Checkpoints {
+    // Stored points, newest first.
+    fn load(&self) -> Result<Vec<Point>, Error>;
+    fn save(&mut self, point: Point) -> Result<(), Error>;
+}
+
+// Checkpoints kept in memory, newest first.
+impl Checkpoints for Vec<Point> {
+    fn load(&self) -> Result<Vec<Point>, Error> {
+        Ok(self.clone())
+    }
+
+    fn save(&mut self, point: Point) -> Result<(), Error> {
+        // Points at or after a rollback point are no longer valid.
+        self.retain(|known| known.slot() < point.slot());
+        self.insert(0, point);
+        Ok(())
+    }
+}
+
+// Checkpoints in a JSON file, limited to `limit` points: the newest half and
+// older points thinned exponentially.
+pub struct FileCheckpoints {
+    path: PathBuf,
+    limit: usize,
+    points: Vec<Point>,
+}
+
+impl FileCheckpoints {
+    pub fn open<P: AsRef<Path>>(path: P, limit: usize) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let points = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| e.to_string())?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.to_string()),
+        };
+        Ok(FileCheckpoints {
+            path,
+            limit,
+            points,
+        })
+    }
+}
+
+impl Checkpoints for FileCheckpoints {
+    fn load(&self) -> Result<Vec<Point>, Error> {
+        self.points.load()
+    }
+
+    fn save(&mut self, point: Point) -> Result<(), Error> {
+        self.points.save(point)?;
+        self.points = thin(&self.points, self.limit);
+        // Replace the file atomically.
+        let data = serde_json::to_vec(&self.points).map_err(|e| e.to_string())?;
+        let temporary = self.path.with_extension("tmp");
+        fs::write(&temporary, data).map_err(|e| e.to_string())?;
+        fs::rename(&temporary, &self.path).map_err(|e| e.to_string())
+    }
+}
+
+// Keep the newest `limit / 2` points, then only the oldest point for each
+// doubling of the slot distance to the newest point, so that rollbacks
+// deeper than `limit` blocks still find an intersection.
+fn thin(points: &[Point], limit: usize) -> Vec<Point> {
+    let dense = limit / 2;
+    let newest = points.first().and_then(Point::slot).unwrap_or(0);
+    let mut kept: Vec<Point> = Vec::new();
+    let mut last_bucket = None;
+    for (index, point) in points.iter().enumerate() {
+        if index >= dense {
+            let distance = newest.saturating_sub(point.slot().unwrap_or(0));
+            let bucket = u64::BITS - distance.leading_zeros();
+            if last_bucket == Some(bucket) {
+                kept.pop();
+            }
+            last_bucket = Some(bucket);
+        }
+        kept.push(point.clone());
+    }
+    // Drop the newest sparse points first, the oldest reach furthest back.
+    if kept.len() > limit {
+        kept.drain(dense..dense + kept.len() - limit);
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{
+        mock_header,
+        Hash,
+        Tip,
+    };
+
+    fn header(block_number: i64) -> BlockHeader {
+        let prev_hash = Hash::new([(block_number as u8).wrapping_sub(1); 32]);
+        mock_header(block_number, block_number * 10, prev_hash)
+    }
+
+    fn point(block_number: i64) -> Point {
+        header(block_number).point()
+    }
+
+    #[test]
+    fn fragment_works() {
+        let tip = Tip {
+            point: Point::Origin,
+            block_number: 0,
+        };
+        let mut fragment = ChainFragment::with_security_param(point(0), 3);
+        for block_number in 1..=3 {
+            let reply = Reply::Forward(header(block_number), tip.clone());
+            assert_eq!(fragment.apply(&reply), Ok(vec![]));
+        }
+        let reply = Reply::Forward(header(4), tip.clone());
+        assert_eq!(
+            fragment.apply(&reply),
+            Ok(vec![FragmentEvent::Immutable(header(1))])
+        );
+        assert_eq!(fragment.anchor(), &point(1));
+        assert_eq!(fragment.tip(), point(4));
+
+        // Rollback within the fragment.
+        let reply = Reply::Backward(point(2), tip.clone());
+        assert_eq!(
+            fragment.apply(&reply),
+            Ok(vec![FragmentEvent::RolledBack(vec![point(4), point(3)])])
+        );
+        assert_eq!(fragment.apply(&reply), Ok(vec![]));
+        assert_eq!(fragment.tip(), point(2));
+
+        // Immutable blocks cannot be rolled back.
+        let reply = Reply::Backward(point(0), tip.clone());
+        assert!(fragment.apply(&reply).is_err());
+        assert_eq!(
+            fragment.apply(&Reply::Backward(point(1), tip)),
+            Ok(vec![FragmentEvent::RolledBack(vec![point(2)])])
+        );
+        assert!(fragment.is_empty());
+        assert_eq!(fragment.tip(), point(1));
+    }
+
+    #[test]
+    fn intersect_points_works() {
+        let mut fragment = ChainFragment::new(Point::Origin);
+        assert_eq!(fragment.intersect_points(), vec![Point::Origin]);
+        for block_number in 1..=20 {
+            fragment.roll_forward(header(block_number));
+        }
+        assert_eq!(
+            fragment.intersect_points(),
+            vec![
+                point(20),
+                point(19),
+                point(17),
+                point(13),
+                point(5),
+                Point::Origin,
+            ]
+        );
+    }
+
+    #[test]
+    fn checkpoints_work() {
+        let path = std::env::temp_dir().join(format!("checkpoints-{}.json", std::process::id()));
+        let mut checkpoints = FileCheckpoints::open(&path, 3).unwrap();
+        assert_eq!(checkpoints.load(), Ok(vec![]));
+        for block_number in 1..=5 {
+            checkpoints.save(point(block_number)).unwrap();
+        }
+        // Rollback replaces the newer points, the oldest one is kept.
+        checkpoints.save(point(4)).unwrap();
+        let checkpoints = FileCheckpoints::open(&path, 3).unwrap();
+        assert_eq!(checkpoints.load(), Ok(vec![point(4), point(3), point(1)]));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn checkpoints_survive_deep_rollback() {
+        let path = std::env::temp_dir().join(format!("deep-{}.json", std::process::id()));
+        let mut checkpoints = FileCheckpoints::open(&path, 8).unwrap();
+        for block_number in 1..=200 {
+            checkpoints.save(point(block_number)).unwrap();
+        }
+        let points = checkpoints.load().unwrap();
+        assert_eq!(points.len(), 8);
+        assert_eq!(
+            points[..4],
+            [point(200), point(199), point(198), point(197)]
+        );
+        // Older points reach back exponentially, newest first.
+        assert!(points
+            .windows(2)
+            .all(|pair| pair[0].slot() > pair[1].slot()));
+        assert!(points[7].slot() <= point(100).slot());
+
+        // Rollback of 150 blocks still leaves older points to intersect.
+        checkpoints.save(point(50)).unwrap();
+        let points = FileCheckpoints::open(&path, 8).unwrap().load().unwrap();
+        assert_eq!(points[0], point(50));
+        assert!(points.len() > 1);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,593 @@
+//
+// © 2022 PERLUR Group
+//
+// SPDX-License-Identifier: MPL-2.0
+//
+
+//
+// Block download from several peers at once. Wanted blocks are split into
+// ranges that peers pick up whenever they have capacity left, so that faster
+// peers end up fetching more of them. Slower peers also get less capacity
+// in proportion to their throughput.
+//
+
+use crate::{
+    model::Point,
+    mux::Connection,
+    protocols::blockfetch::{
+        self,
+        block_hash,
+        Fetched,
+        Range,
+    },
+    Error,
+};
+use futures::future::join_all;
+use log::warn;
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::sync::Notify;
+
+// Blocks per `RequestRange`.
+const RANGE_SIZE: usize = 50;
+// Bytes requested from the fastest peer and not received yet.
+const MAX_IN_FLIGHT: u64 = 4 * 1024 * 1024;
+
+pub fn builder() -> DownloadBuilder {
+    DownloadBuilder {
+        range_size: RANGE_SIZE,
+        max_in_flight: MAX_IN_FLIGHT,
+    }
+}
+
+pub struct DownloadBuilder {
+    range_size: usize,
+    max_in_flight: u64,
+}
+
+impl DownloadBuilder {
+    pub fn range_size(&mut self, blocks: usize) -> &mut Self {
+        self.range_size = blocks.max(1);
+        self
+    }
+
+    pub fn max_in_flight(&mut self, bytes: u64) -> &mut Self {
+        self.max_in_flight = bytes;
+        self
+    }
+
+    // Points and sizes of consecutive blocks, e.g. `header.point()` and
+    // `header.block_size` of ChainSync headers.
+    pub fn build<I: IntoIterator<Item = (Point, u64)>>(&self, wanted: I) -> Download {
+        let wanted: Vec<(Point, u64)> = wanted.into_iter().collect();
+        Download {
+            schedule: Mutex::new(Schedule {
+                max_in_flight: self.max_in_flight,
+                pending: wanted
+                    .chunks(self.range_size)
+                    .map(|blocks| Chunk::new(blocks.to_vec()))
+                    .collect(),
+                peers: Vec::new(),
+                missing: Vec::new(),
+            }),
+            notify: Notify::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeerStats {
+    // Bytes of requested blocks not received yet, as announced by headers.
+    pub in_flight: u64,
+    pub received: u64,
+    pub blocks: u64,
+    // Time spent with requests outstanding.
+    pub busy: Duration,
+    pub connected: bool,
+}
+
+impl PeerStats {
+    // Bytes per second while busy.
+    pub fn throughput(&self) -> f64 {
+        match self.busy.as_secs_f64() {
+            busy if busy > 0.0 => self.received as f64 / busy,
+            _ => 0.0,
+        }
+    }
+}
+
+pub struct Download {
+    schedule: Mutex<Schedule>,
+    // Wakes up idle peers when ranges are requeued or finished.
+    notify: Notify,
+}
+
+impl Download {
+    // Fetch all wanted blocks, `on_block` is called in arrival order which
+    // is only ordered per peer. Fails if some blocks are not available.
+    pub async fn run<F>(&self, connections: &[&Connection], on_block: F) -> Result<(), Error>
+    where
+        F: Fn(Point, Box<[u8]>),
+    {
+        let peers: Vec<usize> = {
+            let mut schedule = self.schedule.lock().unwrap();
+            connections.iter().map(|_| schedule.add_peer()).collect()
+        };
+        join_all(connections.iter().zip(peers).map(|(connection, peer)| {
+            let on_block = &on_block;
+            async move {
+                if let Err(e) = self.fetch(peer, connection, on_block).await {
+                    warn!("Peer {} failed: {}", peer, e);
+                    self.schedule.lock().unwrap().disconnected(peer);
+                    self.notify.notify_waiters();
+                }
+            }
+        }))
+        .await;
+        let schedule = self.schedule.lock().unwrap();
+        match (schedule.missing.is_empty(), schedule.pending.is_empty()) {
+            (true, true) => Ok(()),
+            (false, _) => Err(format!(
+                "{} blocks not available from any peer.",
+                schedule.missing.iter().map(Chunk::len).sum::<usize>()
+            )),
+            (true, false) => Err("All peers disconnected.".to_string()),
+        }
+    }
+
+    pub fn stats(&self) -> Vec<PeerStats> {
+        let schedule = self.schedule.lock().unwrap();
+        schedule.peers.iter().map(Peer::stats).collect()
+    }
+
+    // Blocks refused by all connected peers.
+    pub fn missing(&self) -> Vec<Point> {
+        let schedule = self.schedule.lock().unwrap();
+        schedule
+            .missing
+            .iter()
+            .flat_map(|chunk| chunk.blocks.iter().map(|(point, _)| point.clone()))
+            .collect()
+    }
+
+    async fn fetch<F>(
+        &self,
+        peer: usize,
+        connection: &Connection,
+        on_block: &F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(Point, Box<[u8]>),
+    {
+        let mut pipeline = blockfetch::builder().pipeline(connection)?;
+        loop {
+            // Created before checking so that no notification is missed.
+            let notified = self.notify.notified();
+            let idle = {
+                let mut schedule = self.schedule.lock().unwrap();
+                if pipeline.is_idle() {
+                    schedule.settle(peer);
+                }
+                while let Some(range) = schedule.assign(peer) {
+                    pipeline.push(range);
+                }
+                if pipeline.is_idle() && schedule.is_finished() {
+                    break;
+                }
+                pipeline.is_idle()
+            };
+            if idle {
+                notified.await;
+                continue;
+            }
+            let fetched = pipeline.next().await?;
+            let block = {
+                let mut schedule = self.schedule.lock().unwrap();
+                match fetched {
+                    Some(Fetched::Block(range, bytes)) => {
+                        Some((schedule.block(peer, &range, &bytes)?, bytes))
+                    }
+                    Some(Fetched::NoBlocks(range)) => {
+                        schedule.no_blocks(peer, &range);
+                        None
+                    }
+                    None => None,
+                }
+            };
+            self.notify.notify_waiters();
+            if let Some((point, bytes)) = block {
+                on_block(point, bytes);
+            }
+        }
+        pipeline.done().await
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Chunk {
+    // Consecutive blocks with their sizes.
+    blocks: Vec<(Point, u64)>,
+    received: usize,
+    // Peers that answered `NoBlocks`.
+    refused: Vec<usize>,
+}
+
+impl Chunk {
+    fn new(blocks: Vec<(Point, u64)>) -> Self {
+        Chunk {
+            blocks,
+            received: 0,
+            refused: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn range(&self) -> Range {
+        let first = self.blocks.first().unwrap().0.clone();
+        let last = self.blocks.last().unwrap().0.clone();
+        Range::new(first, last)
+    }
+
+    fn remaining(&self) -> u64 {
+        self.blocks[self.received..]
+            .iter()
+            .map(|(_, size)| size)
+            .sum()
+    }
+}
+
+struct Peer {
+    // Chunks in request order, as the server answers them.
+    requested: VecDeque<Chunk>,
+    stats: PeerStats,
+    busy_since: Option<Instant>,
+}
+
+impl Peer {
+    fn stats(&self) -> PeerStats {
+        let mut stats = self.stats.clone();
+        if let Some(since) = self.busy_since {
+            stats.busy += since.elapsed();
+        }
+        stats
+    }
+
+    fn pop(&mut self) -> Option<Chunk> {
+        let chunk = self.requested.pop_front()?;
+        self.stats.in_flight -= chunk.remaining();
+        if self.requested.is_empty() {
+            if let Some(since) = self.busy_since.take() {
+                self.stats.busy += since.elapsed();
+            }
+        }
+        Some(chunk)
+    }
+}
+
+struct Schedule {
+    max_in_flight: u64,
+    pending: VecDeque<Chunk>,
+    peers: Vec<Peer>,
+    missing: Vec<Chunk>,
+}
+
+impl Schedule {
+    fn add_peer(&mut self) -> usize {
+        self.peers.push(Peer {
+            requested: VecDeque::new(),
+            stats: PeerStats {
+                connected: true,
+                ..Default::default()
+            },
+            busy_since: None,
+        });
+        self.peers.len() - 1
+    }
+
+    fn is_finished(&self) -> bool {
+        self.pending.is_empty() && self.peers.iter().all(|peer| peer.requested.is_empty())
+    }
+
+    // In-flight limit of `peer`, scaled by its throughput relative to the
+    // fastest peer. Peers without measurements get the full limit.
+    fn capacity(&self, peer: usize) -> u64 {
+        let stats = self.peers[peer].stats();
+        let fastest = self
+            .peers
+            .iter()
+            .filter(|peer| peer.stats.connected)
+            .map(|peer| peer.stats().throughput())
+            .fold(0.0, f64::max);
+        match stats.received == 0 || fastest == 0.0 {
+            true => self.max_in_flight,
+            false => ((self.max_in_flight as f64 * stats.throughput() / fastest) as u64).max(1),
+        }
+    }
+
+    fn assign(&mut self, peer: usize) -> Option<Range> {
+        let stats = &self.peers[peer].stats;
+        if !stats.connected || stats.in_flight >= self.capacity(peer) {
+            return None;
+        }
+        let index = self
+            .pending
+            .iter()
+            .position(|chunk| !chunk.refused.contains(&peer))?;
+        let chunk = self.pending.remove(index).unwrap();
+        let range = chunk.range();
+        let peer = &mut self.peers[peer];
+        peer.stats.in_flight += chunk.remaining();
+        peer.busy_since.get_or_insert_with(Instant::now);
+        peer.requested.push_back(chunk);
+        Some(range)
+    }
+
+    fn block(&mut self, peer: usize, range: &Range, bytes: &[u8]) -> Result<Point, Error> {
+        // Chunks before `range` were answered with fewer blocks.
+        loop {
+            match self.peers[peer].requested.front() {
+                Some(chunk) if chunk.range() == *range => break,
+                Some(_) => {
+                    let chunk = self.peers[peer].pop().unwrap();
+                    self.requeue(chunk);
+                }
+                None => return Err(format!("Unexpected block for {:?}.", range)),
+            }
+        }
+        let peer = &mut self.peers[peer];
+        let chunk = peer.requested.front_mut().unwrap();
+        let (point, size) = chunk.blocks[chunk.received].clone();
+        // On a mismatch the peer is dropped, which requeues its chunks.
+        match (&point, block_hash(bytes)?) {
+            (Point::Specific(_, expected), hash) if *expected == hash => (),
+            (_, hash) => return Err(format!("Received block {} instead of {:?}.", hash, point)),
+        }
+        chunk.received += 1;
+        let complete = chunk.received == chunk.len();
+        peer.stats.in_flight -= size;
+        peer.stats.received += bytes.len() as u64;
+        peer.stats.blocks += 1;
+        if complete {
+            peer.pop();
+        }
+        Ok(point)
+    }
+
+    fn no_blocks(&mut self, peer: usize, range: &Range) {
+        let index = self.peers[peer]
+            .requested
+            .iter()
+            .position(|chunk| chunk.range() == *range);
+        if let Some(index) = index {
+            // Chunks before it were answered with fewer blocks.
+            for _ in 0..=index {
+                let mut chunk = self.peers[peer].pop().unwrap();
+                if chunk.range() == *range {
+                    chunk.refused.push(peer);
+                }
+                self.requeue(chunk);
+            }
+        }
+    }
+
+    // Requeue chunks the server did not finish, called with no requests
+    // outstanding.
+    fn settle(&mut self, peer: usize) {
+        while let Some(chunk) = self.peers[peer].pop() {
+            self.requeue(chunk);
+        }
+    }
+
+    fn disconnected(&mut self, peer: usize) {
+        self.peers[peer].stats.connected = false;
+        self.settle(peer);
+        // Nobody else may have the pending chunks.
+        for chunk in std::mem::take(&mut self.pending) {
+            self.requeue(chunk);
+        }
+    }
+
+    fn requeue(&mut self, mut chunk: Chunk) {
+        chunk.blocks.drain(..chunk.received);
+        chunk.received = 0;
+        if chunk.blocks.is_empty() {
+            return;
+        }
+        let available = self
+            .peers
+            .iter()
+            .enumerate()
+            .any(|(index, peer)| peer.stats.connected && !chunk.refused.contains(&index));
+        match available {
+            // Keep the chain order as far as possible.
+            true => {
+                let index = self
+                    .pending
+                    .iter()
+                    .position(|pending| pending.blocks[0].0.slot() > chunk.blocks[0].0.slot())
+                    .unwrap_or(self.pending.len());
+                self.pending.insert(index, chunk);
+            }
+            false if self.peers.iter().any(|peer| peer.stats.connected) => self.missing.push(chunk),
+            // Reported as disconnected.
+            false => self.pending.push_back(chunk),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        model::Hash,
+        protocols::{
+            blockfetch::Message,
+            Message as _,
+            Values,
+        },
+    };
+    use serde_cbor::Value;
+
+    // Era 1 block with the slot number as its header.
+    fn block(slot: u64) -> Vec<u8> {
+        let block = vec![
+            Value::Integer(slot as i128),
+            Value::Array(vec![]),
+            Value::Array(vec![]),
+            Value::Map(Default::default()),
+        ];
+        serde_cbor::to_vec(&Value::Array(vec![Value::Integer(1), Value::Array(block)])).unwrap()
+    }
+
+    fn point(slot: u64) -> Point {
+        let header = serde_cbor::to_vec(&Value::Integer(slot as i128)).unwrap();
+        Point::Specific(slot, Hash::blake2b(&header))
+    }
+
+    fn wanted(count: u64) -> Vec<(Point, u64)> {
+        (1..=count).map(|slot| (point(slot), 10)).collect()
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Behaviour {
+        Serve,
+        Refuse,
+        // Send the blocks of the following slots.
+        Corrupt,
+    }
+
+    // Serves `blocks` until asked to stop, disconnecting after `requests`
+    // requests.
+    async fn serve(connection: Connection, behaviour: Behaviour, requests: usize) {
+        let blocks = wanted(100);
+        let mut channel = connection.channel(0x8003);
+        for _ in 0..requests {
+            let bytes = match channel.recv().await {
+                Ok(bytes) => bytes,
+                Err(_) => return,
+            };
+            let values: Vec<Value> = serde_cbor::from_slice(&bytes).unwrap();
+            let (first, last) = match Message::from_iter(Values::from_vec(&values)).unwrap() {
+                Message::RequestRange(first, last) => (first, last),
+                Message::ClientDone => return,
+                other => panic!("Unexpected message {:?}", other),
+            };
+            if behaviour == Behaviour::Refuse {
+                channel.send(&Message::NoBlocks.to_bytes()).await.unwrap();
+                continue;
+            }
+            channel.send(&Message::StartBatch.to_bytes()).await.unwrap();
+            for (point, _) in &blocks {
+                if point.slot() >= first.slot() && point.slot() <= last.slot() {
+                    let slot = match behaviour {
+                        Behaviour::Corrupt => point.slot().unwrap() + 1,
+                        _ => point.slot().unwrap(),
+                    };
+                    channel
+                        .send(&Message::Block(block(slot)).to_bytes())
+                        .await
+                        .unwrap();
+                }
+            }
+            channel.send(&Message::BatchDone.to_bytes()).await.unwrap();
+        }
+    }
+
+    async fn download(behaviour: Behaviour, requests: usize) -> (Vec<u64>, Vec<PeerStats>) {
+        let (first, first_endpoint) = Connection::test_unix_pair().unwrap();
+        let (second, second_endpoint) = Connection::test_unix_pair().unwrap();
+        let download = builder().range_size(3).max_in_flight(60).build(wanted(20));
+        let received = Mutex::new(Vec::new());
+        let connections = [&first, &second];
+        let (result, _, _) = tokio::join!(
+            download.run(&connections, |point, bytes| {
+                // Blocks match their points.
+                assert_eq!(*bytes, block(point.slot().unwrap()));
+                received.lock().unwrap().push(point.slot().unwrap());
+            }),
+            serve(first_endpoint, Behaviour::Serve, usize::MAX),
+            serve(second_endpoint, behaviour, requests),
+        );
+        assert_eq!(result, Ok(()));
+        let mut received = received.into_inner().unwrap();
+        received.sort_unstable();
+        (received, download.stats())
+    }
+
+    #[tokio::test]
+    async fn download_works() {
+        let (received, stats) = download(Behaviour::Serve, usize::MAX).await;
+        assert_eq!(received, (1..=20).collect::<Vec<_>>());
+        assert_eq!(stats.iter().map(|stats| stats.blocks).sum::<u64>(), 20);
+        assert!(stats.iter().all(|stats| stats.in_flight == 0));
+    }
+
+    #[tokio::test]
+    async fn download_handles_refusing_peer() {
+        let (received, stats) = download(Behaviour::Refuse, usize::MAX).await;
+        assert_eq!(received, (1..=20).collect::<Vec<_>>());
+        assert_eq!(stats[1].blocks, 0);
+    }
+
+    #[tokio::test]
+    async fn download_handles_disconnect() {
+        let (received, stats) = download(Behaviour::Serve, 1).await;
+        assert_eq!(received, (1..=20).collect::<Vec<_>>());
+        assert!(!stats[1].connected);
+    }
+
+    #[tokio::test]
+    async fn download_drops_peer_sending_wrong_blocks() {
+        let (received, stats) = download(Behaviour::Corrupt, 1).await;
+        assert_eq!(received, (1..=20).collect::<Vec<_>>());
+        assert_eq!(stats[1].blocks, 0);
+        assert!(!stats[1].connected);
+    }
+
+    #[tokio::test]
+    async fn download_reports_missing_blocks() {
+        let (connection, endpoint) = Connection::test_unix_pair().unwrap();
+        let download = builder().range_size(5).build(wanted(10));
+        let connections = [&connection];
+        let (result, _) = tokio::join!(
+            download.run(&connections, |_, _| ()),
+            serve(endpoint, Behaviour::Refuse, usize::MAX),
+        );
+        assert_eq!(
+            result,
+            Err("10 blocks not available from any peer.".to_string())
+        );
+        assert_eq!(download.missing().len(), 10);
+    }
+
+    #[test]
+    fn capacity_follows_throughput() {
+        let download = builder().range_size(1).max_in_flight(100).build(wanted(20));
+        let mut schedule = download.schedule.into_inner().unwrap();
+        let (fast, slow, new) = (
+            schedule.add_peer(),
+            schedule.add_peer(),
+            schedule.add_peer(),
+        );
+        for (peer, received) in [(fast, 1000), (slow, 250)] {
+            schedule.peers[peer].stats.received = received;
+            schedule.peers[peer].stats.busy = Duration::from_secs(1);
+        }
+        assert_eq!(schedule.capacity(fast), 100);
+        assert_eq!(schedule.capacity(slow), 25);
+        assert_eq!(schedule.capacity(new), 100);
+        let count =
+            |schedule: &mut Schedule, peer| std::iter::from_fn(|| schedule.assign(peer)).count();
+        assert_eq!(count(&mut schedule, fast), 10);
+        assert_eq!(count(&mut schedule, slow), 3);
+    }
+}
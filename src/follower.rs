@@ -0,0 +1,526 @@
+//
+// © 2022 PERLUR Group
+//
+// SPDX-License-Identifier: MPL-2.0
+//
+
+//
+// Chain follower running handshake, ChainSync and BlockFetch on a single
+// connection, producing blocks in chain order.
+//
+
+use crate::{
+    model::{
+        BlockHeader,
+        Point,
+    },
+    mux::Connection,
+    protocols::{
+        blockfetch::{
+            self,
+            block_hash,
+            Fetched,
+            Pipeline,
+            Range,
+        },
+        chainsync::{
+            self,
+            ChainEvent,
+            ChainSync,
+            Intersect,
+            Progress,
+            State,
+        },
+        handshake,
+        Protocol,
+    },
+    Error,
+};
+use log::warn;
+use std::collections::VecDeque;
+
+#[cfg(feature = "block")]
+use crate::block::Block;
+
+// Headers collected before their blocks are fetched in one range.
+const BATCH_SIZE: usize = 100;
+
+pub fn builder() -> ChainFollowerBuilder {
+    ChainFollowerBuilder {
+        magic: 0,
+        points: vec![Point::Origin],
+        from_origin: false,
+        batch_size: BATCH_SIZE,
+    }
+}
+
+pub struct ChainFollowerBuilder {
+    magic: u32,
+    points: Vec<Point>,
+    from_origin: bool,
+    batch_size: usize,
+}
+
+impl ChainFollowerBuilder {
+    pub fn network_magic(&mut self, magic: u32) -> &mut Self {
+        self.magic = magic;
+        self
+    }
+
+    // Points to start from, newest first.
+    pub fn intersect(&mut self, points: Vec<Point>) -> &mut Self {
+        self.points = points;
+        self
+    }
+
+    // Start from genesis when none of the points is known to the peer,
+    // instead of failing.
+    pub fn from_origin_if_not_found(&mut self) -> &mut Self {
+        self.from_origin = true;
+        self
+    }
+
+    pub fn batch_size(&mut self, headers: usize) -> &mut Self {
+        self.batch_size = headers.max(1);
+        self
+    }
+
+    pub async fn start<'a>(
+        &self,
+        connection: &'a mut Connection,
+    ) -> Result<ChainFollower<'a>, Error> {
+        let outcome = handshake::builder()
+            .node_to_node()
+            .network_magic(self.magic)
+            .client(connection)?
+            .negotiate()
+            .await?;
+        if let handshake::Outcome::Queried(_) = outcome {
+            return Err("Peer sent its versions instead of accepting one.".to_string());
+        }
+        let connection: &Connection = connection;
+        let mut chainsync = chainsync::builder().client(connection);
+        let point = match chainsync.find_intersect(self.points.clone()).await? {
+            Intersect::Found(point, _) => point,
+            Intersect::NotFound(tip) if self.from_origin => {
+                warn!("No intersection found, tip is {:?}.", tip);
+                match chainsync.find_intersect(vec![Point::Origin]).await? {
+                    Intersect::Found(point, _) => point,
+                    Intersect::NotFound(_) => {
+                        chainsync.done().await?;
+                        return Err("Origin not found.".to_string());
+                    }
+                }
+            }
+            Intersect::NotFound(tip) => {
+                chainsync.done().await?;
+                return Err(format!("No intersection found, tip is {:?}.", tip));
+            }
+        };
+        Ok(ChainFollower {
+            chainsync,
+            blockfetch: blockfetch::builder().depth(1).pipeline(connection)?,
+            batch_size: self.batch_size,
+            point,
+            events: VecDeque::new(),
+            resync: None,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+pub enum BlockEvent {
+    RollForward(BlockHeader, Box<[u8]>),
+    RollBackward(Point),
+}
+
+impl BlockEvent {
+    #[cfg(feature = "block")]
+    pub fn block(&self) -> Option<Result<Block, Error>> {
+        match self {
+            BlockEvent::RollForward(_, bytes) => Some(Block::try_from(&**bytes)),
+            BlockEvent::RollBackward(_) => None,
+        }
+    }
+}
+
+pub struct ChainFollower<'a> {
+    chainsync: ChainSync<'a>,
+    blockfetch: Pipeline<'a>,
+    batch_size: usize,
+    // Point of the last event.
+    point: Point,
+    events: VecDeque<BlockEvent>,
+    // Point to find again after blocks could not be fetched.
+    resync: Option<Point>,
+}
+
+impl ChainFollower<'_> {
+    pub fn point(&self) -> &Point {
+        &self.point
+    }
+
+    pub fn progress(&self) -> &Progress {
+        self.chainsync.progress()
+    }
+
+    pub async fn next(&mut self) -> Result<BlockEvent, Error> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                self.point = match &event {
+                    BlockEvent::RollForward(header, _) => header.point(),
+                    BlockEvent::RollBackward(point) => point.clone(),
+                };
+                return Ok(event);
+            }
+            self.fill().await?;
+        }
+    }
+
+    // Terminate ChainSync, the connection can be reused afterwards.
+    pub async fn done(&mut self) -> Result<(), Error> {
+        self.chainsync.done().await
+    }
+
+    async fn fill(&mut self) -> Result<(), Error> {
+        if let Some(point) = self.resync.take() {
+            // Reply to `MsgRequestNext` may still be outstanding.
+            if let State::CanAwait | State::MustReply = self.chainsync.state() {
+                self.chainsync.request_next().await?;
+            }
+            match self.chainsync.find_intersect(vec![point]).await? {
+                Intersect::Found(..) => (),
+                Intersect::NotFound(_) => return Err("Lost intersection.".to_string()),
+            }
+        }
+        let mut headers: Vec<BlockHeader> = Vec::new();
+        let mut rollback = None;
+        while headers.len() < self.batch_size {
+            // Only wait at the tip when there is nothing to deliver.
+            let event = match headers.is_empty() {
+                true => match self.chainsync.request_next().await? {
+                    chainsync::Reply::Forward(header, tip) => ChainEvent::RollForward(header, tip),
+                    chainsync::Reply::Backward(point, tip) => ChainEvent::RollBackward(point, tip),
+                },
+                false => self.chainsync.next_event().await?,
+            };
+            match event {
+                ChainEvent::RollForward(header, _) => headers.push(header),
+                ChainEvent::RollBackward(point, _) => {
+                    match headers.iter().position(|header| header.point() == point) {
+                        Some(index) => headers.truncate(index + 1),
+                        None => {
+                            // Collected headers are all newer than `point`.
+                            headers.clear();
+                            if point != self.point {
+                                rollback = Some(point);
+                                break;
+                            }
+                        }
+                    }
+                }
+                ChainEvent::AtTip => break,
+            }
+        }
+        if let (Some(first), Some(last)) = (headers.first(), headers.last()) {
+            self.blockfetch
+                .push(Range::new(first.point(), last.point()));
+            let count = headers.len();
+            let mut headers = headers.into_iter();
+            let mut fetched = 0;
+            while let Some(result) = self.blockfetch.next().await? {
+                match (result, headers.next()) {
+                    (Fetched::Block(_, bytes), Some(header)) => {
+                        verify(&header, &bytes)?;
+                        self.events
+                            .push_back(BlockEvent::RollForward(header, bytes));
+                        fetched += 1;
+                    }
+                    (Fetched::Block(..), None) => return Err("Unexpected block.".to_string()),
+                    (Fetched::NoBlocks(_), _) => break,
+                }
+            }
+            if fetched < count {
+                // Blocks are gone, probably rolled back. Continue from the
+                // last delivered block, ChainSync will tell what happened.
+                warn!("Fetched only {} of {} blocks.", fetched, count);
+                self.resync = Some(match self.events.back() {
+                    Some(BlockEvent::RollForward(header, _)) => header.point(),
+                    _ => self.point.clone(),
+                });
+                return Ok(());
+            }
+        }
+        if let Some(point) = rollback {
+            self.events.push_back(BlockEvent::RollBackward(point));
+        }
+        Ok(())
+    }
+}
+
+// Check that `bytes` is the block announced by `header`.
+fn verify(header: &BlockHeader, bytes: &[u8]) -> Result<(), Error> {
+    let hash = block_hash(bytes)?;
+    if hash != header.hash {
+        return Err(format!(
+            "Block hash {} expected, found {}.",
+            header.hash, hash
+        ));
+    }
+    #[cfg(feature = "block")]
+    Block::try_from(bytes)?.verify(header)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        model::{
+            mock_header,
+            Hash,
+            Tip,
+        },
+        mux::Channel,
+        protocols::{
+            blockfetch::Message as BlockFetchMessage,
+            chainsync::Message as ChainSyncMessage,
+            Message as _,
+            WrappedBlockHeader,
+        },
+    };
+
+    // Transaction bodies, witness sets and auxiliary data of an empty block.
+    const EMPTY_BODY: [u8; 3] = [0x80, 0x80, 0xa0];
+
+    // Header as decoded from the wire, with the hash of its bytes.
+    fn header(block_number: i64, prev_hash: Hash<32>) -> BlockHeader {
+        let hashes: Vec<u8> = EMPTY_BODY
+            .iter()
+            .flat_map(|segment| Hash::<32>::blake2b(&[*segment]).to_vec())
+            .collect();
+        let header = BlockHeader {
+            block_size: EMPTY_BODY.len() as i64,
+            block_body_hash: Hash::blake2b(&hashes),
+            ..mock_header(block_number, block_number * 10, prev_hash)
+        };
+        WrappedBlockHeader::try_from(header)
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    // Empty Shelley block announced by `header`.
+    fn block(header: &BlockHeader) -> Vec<u8> {
+        let mut bytes = vec![0x82, 0x02, 0x84];
+        bytes.extend(&header.bytes);
+        bytes.extend(EMPTY_BODY);
+        bytes
+    }
+
+    async fn reply(channel: &mut Channel<'_>, request: ChainSyncMessage, reply: ChainSyncMessage) {
+        channel.expect(&request.to_bytes()).await;
+        channel.send(&reply.to_bytes()).await.unwrap();
+    }
+
+    async fn serve(
+        channel: &mut Channel<'_>,
+        range: (&BlockHeader, &BlockHeader),
+        blocks: &[Vec<u8>],
+    ) {
+        let request = BlockFetchMessage::RequestRange(range.0.point(), range.1.point());
+        channel.expect(&request.to_bytes()).await;
+        if blocks.is_empty() {
+            channel
+                .send(&BlockFetchMessage::NoBlocks.to_bytes())
+                .await
+                .unwrap();
+            return;
+        }
+        channel
+            .send(&BlockFetchMessage::StartBatch.to_bytes())
+            .await
+            .unwrap();
+        for block in blocks {
+            let message = BlockFetchMessage::Block(block.to_vec());
+            channel.send(&message.to_bytes()).await.unwrap();
+        }
+        channel
+            .send(&BlockFetchMessage::BatchDone.to_bytes())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn follower_works() {
+        let (mut connection, mut endpoint) = Connection::test_unix_pair().unwrap();
+        let first = header(1, Hash::new([0; 32]));
+        let second = header(2, first.hash);
+        let third = header(3, second.hash);
+        let fork = header(2, Hash::new([9; 32]));
+        let fourth = header(4, first.hash);
+        let tip = Tip {
+            point: third.point(),
+            block_number: 3,
+        };
+        let forward = |header: &BlockHeader| {
+            ChainSyncMessage::RollForward(header.clone().try_into().unwrap(), tip.clone())
+        };
+        let backward = |point: Point| ChainSyncMessage::RollBackward(point, tip.clone());
+        tokio::join!(
+            async {
+                let mut follower = builder()
+                    .network_magic(42)
+                    .intersect(vec![Point::Origin])
+                    .start(&mut connection)
+                    .await
+                    .unwrap();
+                let expected = [
+                    BlockEvent::RollForward(first.clone(), block(&first).into()),
+                    BlockEvent::RollForward(second.clone(), block(&second).into()),
+                    BlockEvent::RollForward(third.clone(), block(&third).into()),
+                    BlockEvent::RollBackward(first.point()),
+                    BlockEvent::RollForward(fourth.clone(), block(&fourth).into()),
+                ];
+                for event in expected {
+                    assert_eq!(follower.next().await, Ok(event));
+                }
+                assert_eq!(follower.point(), &fourth.point());
+            },
+            async {
+                handshake::builder()
+                    .node_to_node()
+                    .network_magic(42)
+                    .server(&mut endpoint)
+                    .unwrap()
+                    .negotiate()
+                    .await
+                    .unwrap();
+                let mut chainsync = endpoint.channel(0x8002);
+                let mut blockfetch = endpoint.channel(0x8003);
+                let request = || ChainSyncMessage::RequestNext;
+                let intersect = ChainSyncMessage::FindIntersect(vec![Point::Origin]);
+                let found = ChainSyncMessage::IntersectFound(Point::Origin, tip.clone());
+                reply(&mut chainsync, intersect, found).await;
+                // Headers are collected until the server is at its tip.
+                reply(&mut chainsync, request(), backward(Point::Origin)).await;
+                reply(&mut chainsync, request(), forward(&first)).await;
+                reply(&mut chainsync, request(), forward(&second)).await;
+                reply(&mut chainsync, request(), forward(&third)).await;
+                reply(&mut chainsync, request(), ChainSyncMessage::AwaitReply).await;
+                let blocks = [block(&first), block(&second), block(&third)];
+                serve(&mut blockfetch, (&first, &third), &blocks).await;
+                chainsync
+                    .send(&backward(first.point()).to_bytes())
+                    .await
+                    .unwrap();
+                // Block of the new fork is gone before it is fetched.
+                reply(&mut chainsync, request(), forward(&fork)).await;
+                reply(&mut chainsync, request(), ChainSyncMessage::AwaitReply).await;
+                serve(&mut blockfetch, (&fork, &fork), &[]).await;
+                chainsync
+                    .send(&backward(first.point()).to_bytes())
+                    .await
+                    .unwrap();
+                let intersect = ChainSyncMessage::FindIntersect(vec![first.point()]);
+                let found = ChainSyncMessage::IntersectFound(first.point(), tip.clone());
+                reply(&mut chainsync, intersect, found).await;
+                reply(&mut chainsync, request(), backward(first.point())).await;
+                reply(&mut chainsync, request(), forward(&fourth)).await;
+                reply(&mut chainsync, request(), ChainSyncMessage::AwaitReply).await;
+                serve(&mut blockfetch, (&fourth, &fourth), &[block(&fourth)]).await;
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn follower_rejects_wrong_block() {
+        let (mut connection, mut endpoint) = Connection::test_unix_pair().unwrap();
+        let first = header(1, Hash::new([0; 32]));
+        let other = header(1, Hash::new([1; 32]));
+        let tip = Tip {
+            point: first.point(),
+            block_number: 1,
+        };
+        tokio::join!(
+            async {
+                let mut follower = builder().start(&mut connection).await.unwrap();
+                assert_eq!(
+                    follower.next().await,
+                    Err(format!(
+                        "Block hash {} expected, found {}.",
+                        first.hash, other.hash
+                    ))
+                );
+            },
+            async {
+                handshake::builder()
+                    .node_to_node()
+                    .server(&mut endpoint)
+                    .unwrap()
+                    .negotiate()
+                    .await
+                    .unwrap();
+                let mut chainsync = endpoint.channel(0x8002);
+                let mut blockfetch = endpoint.channel(0x8003);
+                let intersect = ChainSyncMessage::FindIntersect(vec![Point::Origin]);
+                let found = ChainSyncMessage::IntersectFound(Point::Origin, tip.clone());
+                reply(&mut chainsync, intersect, found).await;
+                let forward = ChainSyncMessage::RollForward(first.clone().try_into().unwrap(), tip);
+                reply(&mut chainsync, ChainSyncMessage::RequestNext, forward).await;
+                let request = ChainSyncMessage::RequestNext;
+                reply(&mut chainsync, request, ChainSyncMessage::AwaitReply).await;
+                serve(&mut blockfetch, (&first, &first), &[block(&other)]).await;
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn follower_handles_unknown_intersection() {
+        let (mut connection, mut endpoint) = Connection::test_unix_pair().unwrap();
+        let tip = Tip {
+            point: Point::Origin,
+            block_number: 0,
+        };
+        let point = Point::Specific(1, Hash::new([1; 32]));
+        for from_origin in [false, true] {
+            let mut builder = builder();
+            builder.intersect(vec![point.clone()]);
+            if from_origin {
+                builder.from_origin_if_not_found();
+            }
+            tokio::join!(
+                async {
+                    match builder.start(&mut connection).await {
+                        Ok(mut follower) => {
+                            assert!(from_origin);
+                            follower.done().await.unwrap();
+                        }
+                        Err(_) => assert!(!from_origin),
+                    }
+                },
+                async {
+                    handshake::builder()
+                        .node_to_node()
+                        .server(&mut endpoint)
+                        .unwrap()
+                        .negotiate()
+                        .await
+                        .unwrap();
+                    let mut chainsync = endpoint.channel(0x8002);
+                    let intersect = ChainSyncMessage::FindIntersect(vec![point.clone()]);
+                    let not_found = ChainSyncMessage::IntersectNotFound(tip.clone());
+                    reply(&mut chainsync, intersect, not_found).await;
+                    if from_origin {
+                        let intersect = ChainSyncMessage::FindIntersect(vec![Point::Origin]);
+                        let found = ChainSyncMessage::IntersectFound(Point::Origin, tip.clone());
+                        reply(&mut chainsync, intersect, found).await;
+                    }
+                    // Client terminates ChainSync in either case.
+                    chainsync.expect(&ChainSyncMessage::Done.to_bytes()).await;
+                },
+            );
+        }
+    }
+}
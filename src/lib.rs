@@ -11,9 +11,16 @@
 // SPDX-License-Identifier: MPL-2.0
 //
 
+#[cfg(feature = "block")]
+pub mod block;
+pub mod chain;
+pub mod download;
+pub mod follower;
 pub mod model;
 pub mod mux;
 pub mod protocols;
+#[cfg(feature = "validation")]
+pub mod validation;
 
 //
 // Error will be string for now. But please use `Result<_, dyn error::Error` if
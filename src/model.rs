@@ -1,46 +1,243 @@
 use crate::Error;
+use blake2b_simd::Params;
+use serde::{
+    de,
+    Deserialize,
+    Deserializer,
+    Serialize,
+    Serializer,
+};
+use std::{
+    fmt,
+    ops::Deref,
+    str::FromStr,
+};
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct Point {
-    pub slot: u64,
-    pub hash: Vec<u8>,
+// Fixed-size byte string used for hashes and keys, rendered as hex.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Hash<const N: usize>([u8; N]);
+
+pub type PoolId = Hash<28>;
+
+impl<const N: usize> Hash<N> {
+    pub const fn new(bytes: [u8; N]) -> Self {
+        Hash(bytes)
+    }
+
+    pub fn blake2b(data: &[u8]) -> Self {
+        let mut bytes = [0u8; N];
+        bytes.copy_from_slice(Params::new().hash_length(N).hash(data).as_bytes());
+        Hash(bytes)
+    }
+}
+
+impl<const N: usize> Deref for Hash<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<const N: usize> AsRef<[u8]> for Hash<N> {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for Hash<N> {
+    fn from(bytes: [u8; N]) -> Self {
+        Hash(bytes)
+    }
+}
+
+impl<const N: usize> TryFrom<&[u8]> for Hash<N> {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Ok(Hash(bytes.try_into().map_err(|_| {
+            format!("Hash of {} bytes required, found {}.", N, bytes.len())
+        })?))
+    }
+}
+
+impl<const N: usize> fmt::Display for Hash<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl<const N: usize> fmt::Debug for Hash<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hash<{}>({})", N, self)
+    }
+}
+
+impl<const N: usize> FromStr for Hash<N> {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        hex::decode(s)
+            .map_err(|_| "Bad hash hex.".to_string())?
+            .as_slice()
+            .try_into()
+    }
+}
+
+impl<const N: usize> Serialize for Hash<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match serializer.is_human_readable() {
+            true => serializer.serialize_str(&self.to_string()),
+            false => serializer.serialize_bytes(&self.0),
+        }
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for Hash<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match deserializer.is_human_readable() {
+            true => String::deserialize(deserializer)?
+                .parse()
+                .map_err(de::Error::custom),
+            false => hex_bytes::deserialize(deserializer)?
+                .as_slice()
+                .try_into()
+                .map_err(de::Error::custom),
+        }
+    }
+}
+
+// Serde helper rendering variable length byte strings as hex.
+pub(crate) mod hex_bytes {
+    use serde::{
+        de,
+        Deserialize,
+        Deserializer,
+        Serializer,
+    };
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match serializer.is_human_readable() {
+            true => serializer.serialize_str(&hex::encode(bytes)),
+            false => serializer.serialize_bytes(bytes),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> de::Visitor<'de> for BytesVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a byte string")
+            }
+
+            fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+                Ok(bytes.to_vec())
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+                Ok(bytes)
+            }
+        }
+
+        match deserializer.is_human_readable() {
+            true => hex::decode(String::deserialize(deserializer)?).map_err(de::Error::custom),
+            false => deserializer.deserialize_bytes(BytesVisitor),
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Point {
+    // Genesis, encoded as an empty array.
+    Origin,
+    Specific(u64, Hash<32>),
+}
+
+impl Point {
+    pub fn slot(&self) -> Option<u64> {
+        match self {
+            Point::Origin => None,
+            Point::Specific(slot, _) => Some(*slot),
+        }
+    }
+
+    pub fn hash(&self) -> Option<&Hash<32>> {
+        match self {
+            Point::Origin => None,
+            Point::Specific(_, hash) => Some(hash),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Tip {
+    pub point: Point,
+    // Zero when the tip is at `Point::Origin`.
     pub block_number: i64,
-    pub slot_number: u64,
-    pub hash: Vec<u8>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct BlockHeader {
     pub block_number: i64,
     pub slot_number: i64,
-    pub hash: Vec<u8>,
-    pub prev_hash: Vec<u8>,
-    pub node_vkey: Vec<u8>,
-    pub node_vrf_vkey: Vec<u8>,
+    pub hash: Hash<32>,
+    pub prev_hash: Hash<32>,
+    pub node_vkey: Hash<32>,
+    pub node_vrf_vkey: Hash<32>,
+    #[serde(with = "hex_bytes")]
     pub eta_vrf_0: Vec<u8>,
+    #[serde(with = "hex_bytes")]
     pub eta_vrf_1: Vec<u8>,
+    #[serde(with = "hex_bytes")]
     pub leader_vrf_0: Vec<u8>,
+    #[serde(with = "hex_bytes")]
     pub leader_vrf_1: Vec<u8>,
     pub block_size: i64,
-    pub block_body_hash: Vec<u8>,
-    pub pool_opcert: Vec<u8>,
-    pub unknown_0: i64,
-    pub unknown_1: i64,
-    pub unknown_2: Vec<u8>,
+    pub block_body_hash: Hash<32>,
+    pub operational_cert: OperationalCert,
     pub protocol_major_version: i64,
     pub protocol_minor_version: i64,
+    // KES signature over `body_bytes`.
+    #[serde(with = "hex_bytes")]
+    pub kes_signature: Vec<u8>,
+    // Original CBOR encoding of the header body.
+    #[serde(with = "hex_bytes")]
+    pub body_bytes: Vec<u8>,
+    // Era index of the header as sent over ChainSync, 1 for Shelley.
+    pub era: u64,
+    // Original CBOR encoding of the whole header.
+    #[serde(with = "hex_bytes")]
+    pub bytes: Vec<u8>,
 }
 
-impl Into<Point> for Tip {
-    fn into(self) -> Point {
-        Point {
-            slot: self.slot_number,
-            hash: self.hash,
-        }
+impl BlockHeader {
+    pub fn point(&self) -> Point {
+        Point::Specific(self.slot_number as u64, self.hash)
+    }
+
+    // Pool id is the Blake2b-224 hash of the cold verification key.
+    pub fn pool_id(&self) -> PoolId {
+        Hash::blake2b(&self.node_vkey)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OperationalCert {
+    pub hot_vkey: Hash<32>,
+    pub sequence_number: u64,
+    pub kes_period: u64,
+    // Cold key signature over the hot key, sequence number and KES period.
+    #[serde(with = "hex_bytes")]
+    pub sigma: Vec<u8>,
+}
+
+impl From<Tip> for Point {
+    fn from(tip: Tip) -> Point {
+        tip.point
     }
 }
 
@@ -49,19 +246,94 @@ impl TryFrom<(u64, &str)> for Point {
 
     fn try_from(pair: (u64, &str)) -> Result<Point, Self::Error> {
         let (slot, hash) = pair;
-        Ok(Point {
-            slot,
-            hash: hex::decode(hash).map_err(|_| "Bad hash hex.".to_string())?,
-        })
+        Ok(Point::Specific(slot, hash.parse()?))
     }
 }
 
-impl From<(u64, &[u8])> for Point {
-    fn from(pair: (u64, &[u8])) -> Point {
+impl From<(u64, Hash<32>)> for Point {
+    fn from(pair: (u64, Hash<32>)) -> Point {
         let (slot, hash) = pair;
-        Point {
-            slot,
-            hash: hash.to_vec(),
-        }
+        Point::Specific(slot, hash)
+    }
+}
+
+// Header with empty keys and signatures, hashed by its block number.
+#[cfg(test)]
+pub(crate) fn mock_header(block_number: i64, slot_number: i64, prev_hash: Hash<32>) -> BlockHeader {
+    BlockHeader {
+        block_number,
+        slot_number,
+        hash: Hash::new([block_number as u8; 32]),
+        prev_hash,
+        node_vkey: Hash::new([0; 32]),
+        node_vrf_vkey: Hash::new([0; 32]),
+        eta_vrf_0: vec![],
+        eta_vrf_1: vec![],
+        leader_vrf_0: vec![],
+        leader_vrf_1: vec![],
+        block_size: 0,
+        block_body_hash: Hash::new([0; 32]),
+        operational_cert: OperationalCert {
+            hot_vkey: Hash::new([0; 32]),
+            sequence_number: 0,
+            kes_period: 0,
+            sigma: vec![],
+        },
+        protocol_major_version: 6,
+        protocol_minor_version: 0,
+        kes_signature: vec![],
+        body_bytes: vec![],
+        era: 1,
+        bytes: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "915386f44ad3a7fccee949c9d3fe43f5a20459c7401f990e1cc7d52c10be1fd6";
+
+    #[test]
+    fn hash_converts() {
+        let hash: Hash<32> = HASH.parse().unwrap();
+        assert_eq!(hash.to_string(), HASH);
+        assert_eq!(hash.as_ref(), hex::decode(HASH).unwrap());
+        assert!(HASH[2..].parse::<Hash<32>>().is_err());
+        assert!(HASH.parse::<PoolId>().is_err());
+        assert!(Hash::<32>::try_from(&[0u8; 31][..]).is_err());
+    }
+
+    #[test]
+    fn hash_serde_works() {
+        let hash: Hash<32> = HASH.parse().unwrap();
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, format!("\"{}\"", HASH));
+        assert_eq!(serde_json::from_str::<Hash<32>>(&json).unwrap(), hash);
+        let cbor = serde_cbor::to_vec(&hash).unwrap();
+        assert_eq!(serde_cbor::from_slice::<Hash<32>>(&cbor).unwrap(), hash);
+        assert!(serde_cbor::from_slice::<Hash<28>>(&cbor).is_err());
+    }
+
+    #[test]
+    fn opcert_serde_works() {
+        let opcert = OperationalCert {
+            hot_vkey: Hash::new([1; 32]),
+            sequence_number: 2,
+            kes_period: 3,
+            sigma: vec![0xab, 0xcd],
+        };
+        let json = serde_json::to_value(&opcert).unwrap();
+        assert_eq!(json["sigma"], "abcd");
+        assert_eq!(json["hot_vkey"], "01".repeat(32));
+        assert_eq!(
+            serde_json::from_value::<OperationalCert>(json).unwrap(),
+            opcert
+        );
+        let cbor = serde_cbor::to_vec(&opcert).unwrap();
+        assert_eq!(
+            serde_cbor::from_slice::<OperationalCert>(&cbor).unwrap(),
+            opcert
+        );
     }
 }
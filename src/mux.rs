@@ -45,11 +45,41 @@ use tokio::{
 use tokio::net::UnixStream;
 
 type Payload = Vec<u8>;
-type Sender<T> = mpsc::UnboundedSender<T>;
-type Receiver<T> = mpsc::UnboundedReceiver<T>;
-type Channels = Arc<std::sync::Mutex<HashMap<u16, Sender<Payload>>>>;
+type Channels = Arc<std::sync::Mutex<HashMap<u16, Sender>>>;
 type Error = String;
 
+// A full bounded queue stops the demux from reading the connection, so the
+// peer is slowed down instead of frames piling up in memory.
+#[derive(Clone)]
+enum Sender {
+    Unbounded(mpsc::UnboundedSender<Payload>),
+    Bounded(mpsc::Sender<Payload>),
+}
+
+impl Sender {
+    // Fails once the channel has been dropped.
+    async fn send(&self, payload: Payload) -> Result<(), Payload> {
+        match self {
+            Sender::Unbounded(sender) => sender.send(payload).map_err(|e| e.0),
+            Sender::Bounded(sender) => sender.send(payload).await.map_err(|e| e.0),
+        }
+    }
+}
+
+enum Receiver {
+    Unbounded(mpsc::UnboundedReceiver<Payload>),
+    Bounded(mpsc::Receiver<Payload>),
+}
+
+impl Receiver {
+    async fn recv(&mut self) -> Option<Payload> {
+        match self {
+            Receiver::Unbounded(receiver) => receiver.recv().await,
+            Receiver::Bounded(receiver) => receiver.recv().await,
+        }
+    }
+}
+
 struct Demux {
     task: task::JoinHandle<Vec<u8>>,
 }
@@ -71,6 +101,8 @@ pub struct Connection {
     sender: Mutex<Box<dyn AsyncWrite + Unpin + Send>>,
     receiver: Arc<Mutex<Box<dyn AsyncRead + Unpin + Send>>>,
     channels: Channels,
+    // Frames queued without waiting, written before the next frame.
+    queued: std::sync::Mutex<Vec<(u16, Payload)>>,
     demux: std::sync::Mutex<Weak<Demux>>,
 }
 
@@ -84,6 +116,7 @@ impl Connection {
             sender: Mutex::new(sender),
             receiver: Arc::new(Mutex::new(receiver)),
             channels: Default::default(),
+            queued: Default::default(),
             demux: Default::default(),
         }
     }
@@ -129,8 +162,22 @@ impl Connection {
         ))
     }
 
-    pub fn channel<'a>(&'a mut self, idx: u16) -> Channel<'a> {
-        let receiver = self.register(idx);
+    // Channels only borrow the connection so that several mini-protocols can
+    // run on it at the same time.
+    pub fn channel<'a>(&'a self, idx: u16) -> Channel<'a> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.attach(idx, Sender::Unbounded(tx), Receiver::Unbounded(rx))
+    }
+
+    // Channel buffering at most `frames` frames, reading from the connection
+    // pauses until they are consumed.
+    pub(crate) fn bounded_channel<'a>(&'a self, idx: u16, frames: usize) -> Channel<'a> {
+        let (tx, rx) = mpsc::channel(frames);
+        self.attach(idx, Sender::Bounded(tx), Receiver::Bounded(rx))
+    }
+
+    fn attach<'a>(&'a self, idx: u16, sender: Sender, receiver: Receiver) -> Channel<'a> {
+        self.register(idx, sender);
         let demux = self.run_demux();
         Channel {
             idx,
@@ -141,31 +188,30 @@ impl Connection {
         }
     }
 
-    fn register(&mut self, idx: u16) -> Receiver<Payload> {
+    fn register(&self, idx: u16, sender: Sender) {
         trace!("Registering channel 0x{:04x}.", idx);
-        let (tx, rx) = mpsc::unbounded_channel();
-        self.channels.lock().unwrap().insert(idx, tx);
-        rx
+        self.channels.lock().unwrap().insert(idx, sender);
     }
-    fn unregister(&mut self, idx: u16) {
+    fn unregister(&self, idx: u16) {
         trace!("Unregistering channel 0x{:04x}.", idx);
         self.channels.lock().unwrap().remove(&idx);
     }
-    async fn send(&self, idx: u16, payload: &[u8]) {
+    async fn send(&self, idx: u16, payload: &[u8]) -> Result<(), io::Error> {
         let mut sender = self.sender.lock().await;
-        let start_time = Instant::now();
-        sender
-            .write_u32(start_time.elapsed().as_micros() as u32)
-            .await
-            .unwrap();
-        sender.write_u16(idx).await.unwrap();
-        sender.write_u16(payload.len() as u16).await.unwrap();
-        sender.write(&payload).await.unwrap();
+        let queued = std::mem::take(&mut *self.queued.lock().unwrap());
+        for (idx, payload) in queued {
+            write_frame(&mut sender, idx, &payload).await?;
+        }
+        write_frame(&mut sender, idx, payload).await
+    }
+    fn queue(&self, idx: u16, payload: &[u8]) {
+        self.queued.lock().unwrap().push((idx, payload.to_vec()));
     }
-    async fn recv(&self, receiver: &mut Receiver<Payload>) -> Vec<u8> {
-        receiver.recv().await.unwrap()
+    // `None` once the connection is closed.
+    async fn recv(&self, receiver: &mut Receiver) -> Option<Vec<u8>> {
+        receiver.recv().await
     }
-    fn run_demux<'a>(&'a self) -> Arc<Demux> {
+    fn run_demux(&self) -> Arc<Demux> {
         let mut demux_lock = self.demux.lock().unwrap();
         match demux_lock.upgrade() {
             Some(demux) => demux,
@@ -176,16 +222,28 @@ impl Connection {
                     let mut receiver = receiver.lock().await;
                     loop {
                         let mut header = [0u8; 8];
-                        receiver.read_exact(&mut header).await.unwrap();
-                        trace!("Header: {}", hex::encode(&header));
-                        let _timestamp = NetworkEndian::read_u32(&mut header[0..4]);
-                        let idx = NetworkEndian::read_u16(&header[4..6]) as u16 ^ 0x8000;
+                        if let Err(e) = receiver.read_exact(&mut header).await {
+                            // Dropping the senders closes all channels.
+                            trace!("Connection closed: {}", e);
+                            channels.lock().unwrap().clear();
+                            return Vec::new();
+                        }
+                        trace!("Header: {}", hex::encode(header));
+                        let _timestamp = NetworkEndian::read_u32(&header[0..4]);
+                        let idx = NetworkEndian::read_u16(&header[4..6]) ^ 0x8000;
                         let length = NetworkEndian::read_u16(&header[6..]) as usize;
                         //trace!("Reading payload, idx={} length={}.", idx, length);
                         let mut payload = vec![0u8; length];
-                        receiver.read_exact(&mut payload).await.unwrap();
-                        match channels.lock().unwrap().get(&idx) {
-                            Some(channel) => channel.send(payload).unwrap(),
+                        if let Err(e) = receiver.read_exact(&mut payload).await {
+                            trace!("Connection closed: {}", e);
+                            channels.lock().unwrap().clear();
+                            return Vec::new();
+                        }
+                        // Not holding the lock while waiting for a bounded channel.
+                        let channel = channels.lock().unwrap().get(&idx).cloned();
+                        match channel {
+                            // Channel may have been dropped already.
+                            Some(channel) => channel.send(payload).await.unwrap_or(()),
                             None => error!("Channel 0x{:04x} not attached.", idx),
                         }
                     }
@@ -197,10 +255,24 @@ impl Connection {
     }
 }
 
+async fn write_frame(
+    sender: &mut Box<dyn AsyncWrite + Unpin + Send>,
+    idx: u16,
+    payload: &[u8],
+) -> Result<(), io::Error> {
+    let start_time = Instant::now();
+    sender
+        .write_u32(start_time.elapsed().as_micros() as u32)
+        .await?;
+    sender.write_u16(idx).await?;
+    sender.write_u16(payload.len() as u16).await?;
+    sender.write_all(payload).await
+}
+
 pub struct Channel<'a> {
     idx: u16,
-    receiver: Receiver<Payload>,
-    connection: &'a mut Connection,
+    receiver: Receiver,
+    connection: &'a Connection,
     _demux: Arc<Demux>,
     pub(crate) bytes: Vec<u8>,
 }
@@ -211,11 +283,23 @@ impl<'a> Channel<'a> {
     }
 
     pub(crate) async fn send(&mut self, data: &[u8]) -> Result<(), Error> {
-        Ok(self.connection.send(self.idx, &data).await)
+        self.connection
+            .send(self.idx, data)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // Best effort send for `Drop`, the data goes out with the next frame
+    // sent on the connection.
+    pub(crate) fn queue(&self, data: &[u8]) {
+        self.connection.queue(self.idx, data)
     }
 
     pub(crate) async fn recv(&mut self) -> Result<Vec<u8>, Error> {
-        Ok(self.connection.recv(&mut self.receiver).await)
+        self.connection
+            .recv(&mut self.receiver)
+            .await
+            .ok_or_else(|| "Connection closed.".to_string())
     }
 
     #[cfg(test)]
@@ -19,6 +19,8 @@ pub mod txsubmission;
 use crate::{
     model::{
         BlockHeader,
+        Hash,
+        OperationalCert,
         Point,
         Tip,
     },
@@ -26,7 +28,6 @@ use crate::{
     Error,
 };
 use async_trait::async_trait;
-use blake2b_simd::Params;
 use log::{
     debug,
     trace,
@@ -46,6 +47,7 @@ pub(crate) trait Protocol<'a> {
     //
     // Static information
     //
+    #[allow(dead_code)]
     fn protocol_id(&self) -> u16;
 
     //
@@ -76,7 +78,7 @@ pub(crate) trait Protocol<'a> {
         Some(bytes)
     }
 
-    fn receive_bytes(&mut self, data: Vec<u8>) -> Option<Box<[u8]>> {
+    fn receive_bytes(&mut self, data: Vec<u8>) -> Result<Option<Box<[u8]>>, Error> {
         debug_assert!(self.agency() != Agency::None);
         debug_assert!(self.agency() != self.role());
         //debug!("Received data length={}", data.len());
@@ -87,9 +89,9 @@ pub(crate) trait Protocol<'a> {
         while let Some(chunk) = d.next() {
             match chunk {
                 Ok(values) => {
-                    let message = Self::Message::from_iter(Values::from_vec(&values)).unwrap();
+                    let message = Self::Message::from_iter(Values::from_vec(&values))?;
                     let info = message.info();
-                    self.recv(message).unwrap();
+                    self.recv(message)?;
                     debug!("Rx: message {}", info);
                     debug!("State: {:?}", self.state());
                     debug!("Demux offset: {}", d.byte_offset());
@@ -97,14 +99,14 @@ pub(crate) trait Protocol<'a> {
                 }
                 Err(e) => match e.is_eof() {
                     true => {
-                        return Some(Box::from(&data[last_offset..]));
+                        return Ok(Some(Box::from(&data[last_offset..])));
                     }
-                    false => panic!("Error: {:?}", e),
+                    false => return Err(format!("Error: {:?}", e)),
                 },
             }
         }
         assert_eq!(d.byte_offset(), data.len());
-        None
+        Ok(None)
     }
 
     async fn execute(&mut self) -> Result<(), Error> {
@@ -117,10 +119,13 @@ pub(crate) trait Protocol<'a> {
                 let data = self.send_bytes().unwrap();
                 self.channel().send(&data).await?;
             } else {
-                let mut bytes = std::mem::replace(&mut self.channel().bytes, Vec::new());
+                let mut bytes = std::mem::take(&mut self.channel().bytes);
                 let new_data = self.channel().recv().await?;
                 bytes.extend(new_data);
-                self.channel().bytes = self.receive_bytes(bytes).unwrap_or(Box::new([])).into_vec();
+                self.channel().bytes = self
+                    .receive_bytes(bytes)?
+                    .unwrap_or(Box::new([]))
+                    .into_vec();
                 if !self.channel().bytes.is_empty() {
                     trace!(
                         "Keeping {} bytes for the next frame.",
@@ -132,7 +137,7 @@ pub(crate) trait Protocol<'a> {
         Ok(())
     }
 
-    fn channel<'b>(&'b mut self) -> &mut Channel<'a>
+    fn channel<'b>(&'b mut self) -> &'b mut Channel<'a>
     where
         'a: 'b;
 }
@@ -143,7 +148,7 @@ pub(crate) trait Message: std::fmt::Debug + Sized {
         panic!()
     }
     fn from_iter(array: Values) -> Result<Self, Error> {
-        Self::from_values(array.to_vec())
+        Self::from_values(array.into_vec())
     }
     fn to_values(&self) -> Vec<Value>;
 
@@ -171,11 +176,11 @@ pub enum Agency {
 pub(crate) struct Values<'a>(std::slice::Iter<'a, Value>);
 
 impl<'a> Values<'a> {
-    pub(crate) fn from_vec(values: &'a Vec<Value>) -> Self {
+    pub(crate) fn from_vec(values: &'a [Value]) -> Self {
         Values(values.iter())
     }
 
-    pub(crate) fn to_vec(self) -> Vec<Value> {
+    pub(crate) fn into_vec(self) -> Vec<Value> {
         self.0.cloned().collect()
     }
 
@@ -214,6 +219,17 @@ impl<'a> Values<'a> {
         }
     }
 
+    pub(crate) fn text(&mut self) -> Result<&String, Error> {
+        match self.0.next() {
+            Some(Value::Text(text)) => Ok(text),
+            other => Err(format!("Text required, found {:?}", other)),
+        }
+    }
+
+    pub(crate) fn hash<const N: usize>(&mut self) -> Result<Hash<N>, Error> {
+        self.bytes()?.as_slice().try_into()
+    }
+
     pub(crate) fn end(mut self) -> Result<(), Error> {
         match self.0.next() {
             None => Ok(()),
@@ -223,29 +239,31 @@ impl<'a> Values<'a> {
 }
 
 pub(crate) fn point_to_vec(point: &Point) -> Vec<Value> {
-    vec![
-        Value::Integer(point.slot.into()),
-        Value::Bytes(point.hash.clone()),
-    ]
+    match point {
+        Point::Origin => vec![],
+        Point::Specific(slot, hash) => {
+            vec![Value::Integer((*slot).into()), Value::Bytes(hash.to_vec())]
+        }
+    }
 }
 
 impl TryInto<Point> for Values<'_> {
     type Error = Error;
 
     fn try_into(mut self) -> Result<Point, Error> {
+        if self.0.len() == 0 {
+            return Ok(Point::Origin);
+        }
         let slot = self.integer()? as u64;
-        let hash = self.bytes()?.clone();
+        let hash = self.hash()?;
         self.end()?;
-        Ok(Point { slot, hash })
+        Ok(Point::Specific(slot, hash))
     }
 }
 
 pub(crate) fn tip_to_vec(tip: &Tip) -> Vec<Value> {
     vec![
-        Value::Array(vec![
-            Value::Integer(tip.slot_number.into()),
-            Value::Bytes(tip.hash.clone()),
-        ]),
+        Value::Array(point_to_vec(&tip.point)),
         Value::Integer(tip.block_number.into()),
     ]
 }
@@ -254,34 +272,40 @@ impl TryInto<Tip> for Values<'_> {
     type Error = Error;
 
     fn try_into(mut self) -> Result<Tip, Error> {
-        let mut tip_info_self = self.array()?;
-        let slot_number = tip_info_self.integer()? as u64;
-        let hash = tip_info_self.bytes()?.clone();
-        tip_info_self.end()?;
+        let point = self.array()?.try_into()?;
         let block_number = self.integer()? as i64;
         self.end()?;
         Ok(Tip {
+            point,
             block_number,
-            slot_number,
-            hash,
         })
     }
 }
 
+// ChainSync era index of Babbage, the first era with Praos headers.
+pub(crate) const PRAOS_ERA: u64 = 5;
+
 #[derive(Debug, Clone, PartialEq)]
 pub(crate) struct WrappedBlockHeader {
-    bytes: Vec<u8>,
+    pub(crate) era: u64,
+    pub(crate) bytes: Vec<u8>,
 }
 
 impl WrappedBlockHeader {
-    fn hash(&self) -> Vec<u8> {
-        Params::new()
-            .hash_length(32)
-            .to_state()
-            .update(&*self.bytes)
-            .finalize()
-            .as_bytes()
-            .to_vec()
+    fn hash(&self) -> Hash<32> {
+        Hash::blake2b(&self.bytes)
+    }
+
+    fn body_bytes(&self) -> Result<&[u8], Error> {
+        // Header is an array of the header body and its KES signature.
+        if self.bytes.first() != Some(&0x82) {
+            return Err("Header array required.".to_string());
+        }
+        let mut items = Deserializer::from_slice(&self.bytes[1..]).into_iter::<Value>();
+        match items.next() {
+            Some(Ok(_)) => Ok(&self.bytes[1..1 + items.byte_offset()]),
+            other => Err(format!("Header body required, found {:?}", other)),
+        }
     }
 }
 
@@ -290,10 +314,10 @@ impl TryInto<WrappedBlockHeader> for Values<'_> {
 
     fn try_into(self) -> Result<WrappedBlockHeader, Error> {
         let mut array = self;
-        array.integer()?;
+        let era = array.integer()? as u64;
         let bytes = array.bytes()?.clone();
         array.end()?;
-        Ok(WrappedBlockHeader { bytes })
+        Ok(WrappedBlockHeader { era, bytes })
     }
 }
 
@@ -302,31 +326,43 @@ impl TryInto<BlockHeader> for WrappedBlockHeader {
 
     fn try_into(self) -> Result<BlockHeader, Self::Error> {
         let hash = self.hash();
-        let value = serde_cbor::from_slice(&self.bytes).map_err(|e| format!("{:?}", e))?;
+        let body_bytes = self.body_bytes()?.to_vec();
+        let value: Vec<Value> =
+            serde_cbor::from_slice(&self.bytes).map_err(|e| format!("{:?}", e))?;
         let mut outer_array = Values::from_vec(&value);
         let mut array = outer_array.array()?;
         let block_number = array.integer()? as i64;
         let slot_number = array.integer()? as i64;
-        let prev_hash = array.bytes()?.to_vec();
-        let node_vkey = array.bytes()?.to_vec();
-        let node_vrf_vkey = array.bytes()?.to_vec();
-        let mut eta_vrf = array.array()?;
-        let eta_vrf_0 = eta_vrf.bytes()?.to_vec();
-        let eta_vrf_1 = eta_vrf.bytes()?.to_vec();
-        eta_vrf.end()?;
-        let mut leader_vrf = array.array()?;
-        let leader_vrf_0 = leader_vrf.bytes()?.to_vec();
-        let leader_vrf_1 = leader_vrf.bytes()?.to_vec();
-        leader_vrf.end()?;
+        let prev_hash = array.hash()?;
+        let node_vkey = array.hash()?;
+        let node_vrf_vkey = array.hash()?;
+        let praos = self.era >= PRAOS_ERA;
+        let ((eta_vrf_0, eta_vrf_1), (leader_vrf_0, leader_vrf_1)) = match praos {
+            // Praos has a single VRF result for the nonce and the leader value.
+            true => {
+                let vrf = vrf_result(&mut array)?;
+                (vrf.clone(), vrf)
+            }
+            false => (vrf_result(&mut array)?, vrf_result(&mut array)?),
+        };
         let block_size = array.integer()? as i64;
-        let block_body_hash = array.bytes()?.to_vec();
-        let pool_opcert = array.bytes()?.to_vec();
-        let unknown_0 = array.integer()? as i64;
-        let unknown_1 = array.integer()? as i64;
-        let unknown_2 = array.bytes()?.to_vec();
-        let protocol_major_version = array.integer()? as i64;
-        let protocol_minor_version = array.integer()? as i64;
-        array.end()?;
+        let block_body_hash = array.hash()?;
+        // Praos nests the opcert and protocol version in arrays.
+        let (operational_cert, mut version) = match praos {
+            true => {
+                let mut opcert = array.array()?;
+                let operational_cert = operational_cert(&mut opcert)?;
+                opcert.end()?;
+                let version = array.array()?;
+                array.end()?;
+                (operational_cert, version)
+            }
+            false => (operational_cert(&mut array)?, array),
+        };
+        let protocol_major_version = version.integer()? as i64;
+        let protocol_minor_version = version.integer()? as i64;
+        version.end()?;
+        let kes_signature = outer_array.bytes()?.to_vec();
         outer_array.end()?;
         Ok(BlockHeader {
             block_number,
@@ -341,45 +377,88 @@ impl TryInto<BlockHeader> for WrappedBlockHeader {
             leader_vrf_1,
             block_size,
             block_body_hash,
-            pool_opcert,
-            unknown_0,
-            unknown_1,
-            unknown_2,
+            operational_cert,
             protocol_major_version,
             protocol_minor_version,
+            kes_signature,
+            body_bytes,
+            era: self.era,
+            bytes: self.bytes,
         })
     }
 }
 
+fn vrf_result(array: &mut Values) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let mut vrf = array.array()?;
+    let output = vrf.bytes()?.to_vec();
+    let proof = vrf.bytes()?.to_vec();
+    vrf.end()?;
+    Ok((output, proof))
+}
+
+fn operational_cert(array: &mut Values) -> Result<OperationalCert, Error> {
+    Ok(OperationalCert {
+        hot_vkey: array.hash()?,
+        sequence_number: array.integer()? as u64,
+        kes_period: array.integer()? as u64,
+        sigma: array.bytes()?.to_vec(),
+    })
+}
+
 impl TryFrom<BlockHeader> for WrappedBlockHeader {
     type Error = Error;
 
+    // Header is re-encoded from the decoded fields, `body_bytes` and `bytes`
+    // are ignored.
+    // Praos headers take the VRF result from the leader fields.
     fn try_from(header: BlockHeader) -> Result<Self, Self::Error> {
-        let value = Value::Array(vec![Value::Array(vec![
+        let mut body = vec![
             Value::Integer(header.block_number.into()),
             Value::Integer(header.slot_number.into()),
-            Value::Bytes(header.prev_hash),
-            Value::Bytes(header.node_vkey),
-            Value::Bytes(header.node_vrf_vkey),
-            Value::Array(vec![
-                Value::Bytes(header.eta_vrf_0),
-                Value::Bytes(header.eta_vrf_1),
-            ]),
-            Value::Array(vec![
-                Value::Bytes(header.leader_vrf_0),
-                Value::Bytes(header.leader_vrf_1),
-            ]),
-            Value::Integer(header.block_size.into()),
-            Value::Bytes(header.block_body_hash),
-            Value::Bytes(header.pool_opcert),
-            Value::Integer(header.unknown_0.into()),
-            Value::Integer(header.unknown_1.into()),
-            Value::Bytes(header.unknown_2),
+            Value::Bytes(header.prev_hash.to_vec()),
+            Value::Bytes(header.node_vkey.to_vec()),
+            Value::Bytes(header.node_vrf_vkey.to_vec()),
+        ];
+        let leader_vrf = Value::Array(vec![
+            Value::Bytes(header.leader_vrf_0),
+            Value::Bytes(header.leader_vrf_1),
+        ]);
+        let opcert = vec![
+            Value::Bytes(header.operational_cert.hot_vkey.to_vec()),
+            Value::Integer(header.operational_cert.sequence_number.into()),
+            Value::Integer(header.operational_cert.kes_period.into()),
+            Value::Bytes(header.operational_cert.sigma),
+        ];
+        let version = vec![
             Value::Integer(header.protocol_major_version.into()),
             Value::Integer(header.protocol_minor_version.into()),
-        ])]);
+        ];
+        let block_size = Value::Integer(header.block_size.into());
+        let block_body_hash = Value::Bytes(header.block_body_hash.to_vec());
+        match header.era >= PRAOS_ERA {
+            true => body.extend([
+                leader_vrf,
+                block_size,
+                block_body_hash,
+                Value::Array(opcert),
+                Value::Array(version),
+            ]),
+            false => {
+                body.push(Value::Array(vec![
+                    Value::Bytes(header.eta_vrf_0),
+                    Value::Bytes(header.eta_vrf_1),
+                ]));
+                body.extend([leader_vrf, block_size, block_body_hash]);
+                body.extend(opcert);
+                body.extend(version);
+            }
+        }
+        let value = Value::Array(vec![Value::Array(body), Value::Bytes(header.kes_signature)]);
         let bytes = to_vec(&value).map_err(|e| format!("{:?}", e))?.to_vec();
-        Ok(WrappedBlockHeader { bytes })
+        Ok(WrappedBlockHeader {
+            era: header.era,
+            bytes,
+        })
     }
 }
 
@@ -389,56 +468,109 @@ mod test {
 
     #[test]
     fn point_converts() {
-        let point = Point {
-            slot: 0x1122334455667788,
-            hash: b"mock-hash".to_vec(),
-        };
-        assert_eq!(
-            point.clone(),
-            Values::from_vec(&point_to_vec(&point)).try_into().unwrap(),
-        );
+        for point in [
+            Point::Origin,
+            Point::Specific(0x1122334455667788, Hash::new([1; 32])),
+        ] {
+            assert_eq!(
+                point.clone(),
+                Values::from_vec(&point_to_vec(&point)).try_into().unwrap(),
+            );
+        }
+        assert_eq!(point_to_vec(&Point::Origin), vec![]);
+    }
+
+    #[test]
+    fn point_rejects_bad_hash() {
+        let values = vec![Value::Integer(42), Value::Bytes(vec![1; 31])];
+        let point: Result<Point, _> = Values::from_vec(&values).try_into();
+        assert!(point.is_err());
     }
 
     #[test]
     fn tip_converts() {
-        let tip = Tip {
-            block_number: 0x1234,
-            slot_number: 0x5678,
-            hash: b"mock-hash".to_vec(),
-        };
-        assert_eq!(
-            tip.clone(),
-            Values::from_vec(&tip_to_vec(&tip)).try_into().unwrap(),
-        );
+        for tip in [
+            Tip {
+                point: Point::Origin,
+                block_number: 0,
+            },
+            Tip {
+                point: Point::Specific(0x5678, Hash::new([1; 32])),
+                block_number: 0x1234,
+            },
+        ] {
+            assert_eq!(
+                tip.clone(),
+                Values::from_vec(&tip_to_vec(&tip)).try_into().unwrap(),
+            );
+        }
     }
 
     #[test]
     fn header_converts() {
-        let mut header = BlockHeader {
+        let header = BlockHeader {
             block_number: 1,
             slot_number: 2,
-            hash: vec![],
-            prev_hash: b"mock-prev-hash".to_vec(),
-            node_vkey: b"mock-node-vkey".to_vec(),
-            node_vrf_vkey: b"mock-node-vrf_vkey".to_vec(),
+            hash: Hash::new([0; 32]),
+            prev_hash: Hash::new([1; 32]),
+            node_vkey: Hash::new([2; 32]),
+            node_vrf_vkey: Hash::new([3; 32]),
             eta_vrf_0: b"mock-eta-vrf-0".to_vec(),
             eta_vrf_1: b"mock-eta-vrf-1".to_vec(),
             leader_vrf_0: b"mock-leader-vrf-0".to_vec(),
             leader_vrf_1: b"mock-leader-vrf-1".to_vec(),
             block_size: 3,
-            block_body_hash: b"mock-block-body-hash".to_vec(),
-            pool_opcert: b"mock-pool-opcert".to_vec(),
-            unknown_0: 4,
-            unknown_1: 5,
-            unknown_2: b"mock-unknown-2".to_vec(),
+            block_body_hash: Hash::new([4; 32]),
+            operational_cert: OperationalCert {
+                hot_vkey: Hash::new([5; 32]),
+                sequence_number: 4,
+                kes_period: 5,
+                sigma: b"mock-sigma".to_vec(),
+            },
             protocol_major_version: 6,
             protocol_minor_version: 7,
+            kes_signature: b"mock-kes-signature".to_vec(),
+            body_bytes: vec![],
+            era: 1,
+            bytes: vec![],
         };
-        // Get the hash computed first.
-        let wrapped: WrappedBlockHeader = header.clone().try_into().unwrap();
-        header.hash = wrapped.hash();
         let wrapped: WrappedBlockHeader = header.clone().try_into().unwrap();
-        assert_eq!(header.hash, wrapped.hash());
-        assert_eq!(header, wrapped.try_into().unwrap(),);
+        let decoded: BlockHeader = wrapped.clone().try_into().unwrap();
+        assert_eq!(decoded.hash, wrapped.hash());
+        // Header body is followed by the KES signature.
+        let mut bytes = vec![0x82];
+        bytes.extend(&decoded.body_bytes);
+        bytes.extend(to_vec(&Value::Bytes(header.kes_signature.clone())).unwrap());
+        assert_eq!(bytes, wrapped.bytes);
+        assert_eq!(decoded.bytes, wrapped.bytes);
+        assert_eq!(
+            BlockHeader {
+                hash: decoded.hash,
+                body_bytes: decoded.body_bytes.clone(),
+                bytes: decoded.bytes.clone(),
+                ..header.clone()
+            },
+            decoded,
+        );
+
+        // Praos headers carry one VRF result, taken from the leader fields.
+        let praos = BlockHeader {
+            era: 5,
+            eta_vrf_0: header.leader_vrf_0.clone(),
+            eta_vrf_1: header.leader_vrf_1.clone(),
+            ..header
+        };
+        let wrapped: WrappedBlockHeader = praos.clone().try_into().unwrap();
+        assert!(wrapped.bytes.len() < bytes.len());
+        let decoded: BlockHeader = wrapped.clone().try_into().unwrap();
+        assert_eq!(
+            BlockHeader {
+                hash: decoded.hash,
+                body_bytes: decoded.body_bytes.clone(),
+                bytes: decoded.bytes.clone(),
+                ..praos
+            },
+            decoded,
+        );
     }
 }
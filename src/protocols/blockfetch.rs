@@ -11,13 +11,33 @@ use crate::mux::Channel;
 use crate::mux::Connection;
 use crate::protocols::Message as MessageOps;
 use crate::{
+    model::Hash,
     model::Point,
+    protocols::point_to_vec,
     protocols::Agency,
     protocols::Protocol,
     protocols::Values,
     Error,
 };
-use serde_cbor::Value;
+use futures::{
+    Future,
+    Stream,
+};
+use serde_cbor::{
+    de::Deserializer,
+    Value,
+};
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+
+#[cfg(feature = "block")]
+use crate::block::Block;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum State {
@@ -46,7 +66,7 @@ impl MessageOps for Message {
             3 => Message::NoBlocks,
             4 => Message::Block(array.bytes()?.to_vec()),
             5 => Message::BatchDone,
-            _ => panic!(),
+            other => return Err(format!("Unexpected message: {}.", other)),
         };
         array.end()?;
         Ok(message)
@@ -56,8 +76,8 @@ impl MessageOps for Message {
         match self {
             Message::RequestRange(first, last) => vec![
                 Value::Integer(0),
-                vec![Value::Integer(first.slot.into()), first.hash.clone().into()].into(),
-                vec![Value::Integer(last.slot.into()), last.hash.clone().into()].into(),
+                Value::Array(point_to_vec(first)),
+                Value::Array(point_to_vec(last)),
             ],
             Message::ClientDone => vec![Value::Integer(1)],
             Message::StartBatch => vec![Value::Integer(2)],
@@ -79,30 +99,76 @@ pub fn builder() -> Builder {
     Default::default()
 }
 
+// Number of outstanding `RequestRange` messages in a pipeline.
+const PIPELINE_DEPTH: usize = 10;
+// Frames of blocks received before they are consumed.
+const READ_AHEAD: usize = 16;
+
 #[derive(Default)]
 pub struct Builder {
     first: Option<Point>,
     last: Option<Point>,
+    depth: Option<usize>,
+    read_ahead: Option<usize>,
 }
 
 impl Builder {
-    pub fn first(&mut self, slot: u64, hash: Vec<u8>) -> &mut Self {
-        self.first = Some((slot, hash.as_slice()).into());
+    pub fn first(&mut self, slot: u64, hash: Hash<32>) -> &mut Self {
+        self.first = Some((slot, hash).into());
+        self
+    }
+    pub fn last(&mut self, slot: u64, hash: Hash<32>) -> &mut Self {
+        self.last = Some((slot, hash).into());
         self
     }
-    pub fn last(&mut self, slot: u64, hash: Vec<u8>) -> &mut Self {
-        self.last = Some((slot, hash.as_slice()).into());
+    pub fn depth(&mut self, depth: usize) -> &mut Self {
+        self.depth = Some(depth);
         self
     }
-    pub fn client<'a>(&mut self, connection: &'a mut Connection) -> Result<BlockFetch<'a>, Error> {
+    // Once `frames` frames are waiting, the connection is not read until the
+    // blocks are consumed. This also holds up other mini-protocols.
+    pub fn read_ahead(&mut self, frames: usize) -> &mut Self {
+        self.read_ahead = Some(frames);
+        self
+    }
+    fn channel<'a>(&self, connection: &'a Connection) -> Result<Channel<'a>, Error> {
+        match self.read_ahead.unwrap_or(READ_AHEAD) {
+            0 => Err("Read-ahead must be positive.".to_string()),
+            frames => Ok(connection.bounded_channel(0x0003, frames)),
+        }
+    }
+    // Client for a queue of ranges, starting with `first`..`last` if set.
+    pub fn pipeline<'a>(&mut self, connection: &'a Connection) -> Result<Pipeline<'a>, Error> {
+        let mut queue = VecDeque::new();
+        match (&self.first, &self.last) {
+            (Some(first), Some(last)) => queue.push_back(Range::new(first.clone(), last.clone())),
+            (None, None) => (),
+            _ => return Err("Both first and last point required.".to_string()),
+        }
+        let depth = self.depth.unwrap_or(PIPELINE_DEPTH);
+        if depth == 0 {
+            return Err("Pipeline depth must be positive.".to_string());
+        }
+        Ok(Pipeline {
+            channel: self.channel(connection)?,
+            depth,
+            queue,
+            requested: VecDeque::new(),
+            state: State::Idle,
+            result: VecDeque::new(),
+            running: false,
+            done: false,
+        })
+    }
+    pub fn client<'a>(&mut self, connection: &'a Connection) -> Result<BlockFetch<'a>, Error> {
         Ok(BlockFetch {
-            channel: connection.channel(0x0003),
+            channel: self.channel(connection)?,
             config: Config {
                 first: self.first.as_ref().ok_or("First point required.")?.clone(),
                 last: self.last.as_ref().ok_or("Last point required.")?.clone(),
             },
             state: State::Idle,
-            result: Vec::new(),
+            result: VecDeque::new(),
             running: false,
             done: false,
         })
@@ -118,7 +184,7 @@ pub struct BlockFetch<'a> {
     channel: Channel<'a>,
     config: Config,
     state: State,
-    result: Vec<Box<[u8]>>,
+    result: VecDeque<Box<[u8]>>,
     running: bool,
     done: bool,
 }
@@ -130,7 +196,10 @@ impl<'a> BlockFetch<'a> {
     {
         self.running = true;
         self.execute().await?;
-        Ok(BlockStream { blockfetch: self })
+        Ok(BlockStream {
+            blockfetch: Some(self),
+            pending: None,
+        })
     }
 
     pub async fn done(mut self) -> Result<(), Error> {
@@ -141,27 +210,69 @@ impl<'a> BlockFetch<'a> {
     }
 }
 
+type PendingFetch<'a, 'b> =
+    Pin<Box<dyn Future<Output = (&'b mut BlockFetch<'a>, Result<(), Error>)> + Send + 'b>>;
+
 pub struct BlockStream<'a, 'b> {
-    blockfetch: &'b mut BlockFetch<'a>,
+    // `None` while a fetch is pending and after the stream has ended.
+    blockfetch: Option<&'b mut BlockFetch<'a>>,
+    pending: Option<PendingFetch<'a, 'b>>,
 }
 
 impl BlockStream<'_, '_> {
-    pub async fn next(&mut self) -> Result<Option<Box<[u8]>>, Error> {
-        if self.blockfetch.result.is_empty() {
-            match self.blockfetch.state() {
+    #[cfg(feature = "block")]
+    pub async fn next_block(&mut self) -> Result<Option<Block>, Error> {
+        match futures::StreamExt::next(self).await {
+            Some(bytes) => Ok(Some(Block::try_from(&*bytes?)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<'a, 'b> Stream for BlockStream<'a, 'b>
+where
+    'a: 'b,
+{
+    type Item = Result<Box<[u8]>, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(pending) = this.pending.as_mut() {
+                let (blockfetch, result) = match pending.as_mut().poll(cx) {
+                    Poll::Ready(output) => output,
+                    Poll::Pending => return Poll::Pending,
+                };
+                this.pending = None;
+                if let Err(e) = result {
+                    // Stream ends on the first error.
+                    return Poll::Ready(Some(Err(e)));
+                }
+                this.blockfetch = Some(blockfetch);
+            }
+            let blockfetch = match this.blockfetch.take() {
+                Some(blockfetch) => blockfetch,
+                None => return Poll::Ready(None),
+            };
+            if let Some(bytes) = blockfetch.result.pop_front() {
+                this.blockfetch = Some(blockfetch);
+                return Poll::Ready(Some(Ok(bytes)));
+            }
+            match blockfetch.state {
                 State::Streaming => {
-                    self.blockfetch.running = true;
-                    self.blockfetch.execute().await?;
+                    blockfetch.running = true;
+                    this.pending = Some(Box::pin(async move {
+                        let result = blockfetch.execute().await;
+                        (blockfetch, result)
+                    }));
+                }
+                State::Idle => return Poll::Ready(None),
+                state => {
+                    let e = format!("Unexpected state: {:?}", state);
+                    return Poll::Ready(Some(Err(e)));
                 }
-                State::Idle => return Ok(None),
-                _ => panic!("Unexpected state."),
             }
         }
-        if self.blockfetch.result.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(self.blockfetch.result.remove(0)))
-        }
     }
 }
 
@@ -218,26 +329,246 @@ impl<'a> Protocol<'a> for BlockFetch<'a> {
 
     fn recv(&mut self, message: Message) -> Result<(), Error> {
         // `self.running` may be false in case of pipelining.
-        Ok(self.state = match (self.state, message) {
+        self.state = match (self.state, message) {
             (State::Busy, Message::NoBlocks) => {
                 self.running = false;
                 State::Idle
             }
             (State::Busy, Message::StartBatch) => State::Streaming,
             (State::Streaming, Message::Block(bytes)) => {
+                self.result.push_back(bytes.into_boxed_slice());
+                // Hand out blocks as they arrive, all blocks of a received
+                // frame are buffered though.
                 self.running = false;
-                self.result.push(bytes.into_boxed_slice());
                 State::Streaming
             }
             (State::Streaming, Message::BatchDone) => {
                 self.running = false;
                 State::Idle
             }
-            (state, message) => panic!("Unexpected message {:?} in state {:?}.", message, state),
-        })
+            (state, message) => {
+                return Err(format!(
+                    "Unexpected message {:?} in state {:?}.",
+                    message, state
+                ))
+            }
+        };
+        Ok(())
+    }
+
+    fn channel<'b>(&'b mut self) -> &'b mut Channel<'a>
+    where
+        'a: 'b,
+    {
+        &mut self.channel
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Range {
+    pub first: Point,
+    pub last: Point,
+}
+
+impl Range {
+    pub fn new(first: Point, last: Point) -> Self {
+        Range { first, last }
+    }
+}
+
+// Hash of a fetched block, i.e. of the original encoding of its header.
+pub fn block_hash(bytes: &[u8]) -> Result<Hash<32>, Error> {
+    // Block is an array of era and block, the latter starting with the header.
+    if bytes.first() != Some(&0x82) {
+        return Err("Block array required.".to_string());
+    }
+    let mut items = Deserializer::from_slice(&bytes[1..]).into_iter::<Value>();
+    match items.next() {
+        Some(Ok(Value::Integer(_))) => (),
+        other => return Err(format!("Block era required, found {:?}", other)),
+    }
+    let block = 1 + items.byte_offset();
+    let header = match bytes.get(block) {
+        Some(0x80..=0x97) | Some(0x9f) => block + 1,
+        _ => return Err("Block contents array required.".to_string()),
+    };
+    let mut items = Deserializer::from_slice(&bytes[header..]).into_iter::<Value>();
+    match items.next() {
+        Some(Ok(_)) => Ok(Hash::blake2b(&bytes[header..header + items.byte_offset()])),
+        other => Err(format!("Block header required, found {:?}", other)),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Fetched {
+    Block(Range, Box<[u8]>),
+    // Server does not have (all of) the range anymore.
+    NoBlocks(Range),
+}
+
+//
+// BlockFetch client sending up to `depth` requests before the blocks of
+// the first one arrive. Ranges are requested in queue order.
+//
+pub struct Pipeline<'a> {
+    channel: Channel<'a>,
+    depth: usize,
+    queue: VecDeque<Range>,
+    // Ranges sent to the server, oldest first.
+    requested: VecDeque<Range>,
+    state: State,
+    result: VecDeque<Fetched>,
+    running: bool,
+    done: bool,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn push(&mut self, range: Range) {
+        self.queue.push_back(range);
+    }
+
+    // Remove a range that has not been requested yet.
+    pub fn cancel(&mut self, range: &Range) -> bool {
+        let length = self.queue.len();
+        self.queue.retain(|queued| queued != range);
+        self.queue.len() != length
+    }
+
+    // Remove all ranges that have not been requested yet.
+    pub fn clear(&mut self) -> Vec<Range> {
+        self.queue.drain(..).collect()
+    }
+
+    pub fn queued(&self) -> impl Iterator<Item = &Range> {
+        self.queue.iter()
+    }
+
+    pub fn requested(&self) -> impl Iterator<Item = &Range> {
+        self.requested.iter()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.requested.is_empty() && self.result.is_empty()
+    }
+
+    // Next block in range order, `None` once the queue is exhausted.
+    pub async fn next(&mut self) -> Result<Option<Fetched>, Error> {
+        while self.result.is_empty() {
+            if self.queue.is_empty() && self.requested.is_empty() {
+                return Ok(None);
+            }
+            self.running = true;
+            self.execute().await?;
+        }
+        Ok(self.result.pop_front())
+    }
+
+    // Drop queued ranges, drain requested ones and send `ClientDone`.
+    pub async fn done(mut self) -> Result<(), Error> {
+        self.queue.clear();
+        self.running = true;
+        self.done = true;
+        self.execute().await
+    }
+
+    // State after a response, pipelined requests are already sent.
+    fn next_state(&self) -> State {
+        match self.requested.is_empty() {
+            true => State::Idle,
+            false => State::Busy,
+        }
+    }
+}
+
+impl<'a> Protocol<'a> for Pipeline<'a> {
+    type State = State;
+    type Message = Message;
+
+    fn protocol_id(&self) -> u16 {
+        0x0003
+    }
+
+    fn role(&self) -> Agency {
+        Agency::Client
+    }
+
+    fn agency(&self) -> Agency {
+        // Closing runs until `ClientDone` is sent.
+        if !(self.running || self.done) || self.state == State::Done {
+            return Agency::None;
+        }
+        if self.requested.len() < self.depth && !self.queue.is_empty() {
+            return Agency::Client;
+        }
+        match (self.requested.is_empty(), self.done) {
+            (false, _) => Agency::Server,
+            (true, true) => Agency::Client,
+            (true, false) => Agency::None,
+        }
     }
 
-    fn channel<'b>(&'b mut self) -> &mut Channel<'a>
+    fn state(&self) -> Self::State {
+        self.state
+    }
+
+    fn send(&mut self) -> Result<Message, Error> {
+        debug_assert!(self.running);
+        match self.queue.pop_front() {
+            Some(range) => {
+                let message = Message::RequestRange(range.first.clone(), range.last.clone());
+                if self.state == State::Idle {
+                    self.state = State::Busy;
+                }
+                self.requested.push_back(range);
+                Ok(message)
+            }
+            None if self.done && self.state == State::Idle => {
+                self.state = State::Done;
+                Ok(Message::ClientDone)
+            }
+            None => Err(format!("Unexpected state: {:?}", self.state)),
+        }
+    }
+
+    fn recv(&mut self, message: Message) -> Result<(), Error> {
+        let range = self
+            .requested
+            .front()
+            .ok_or_else(|| format!("Unexpected message {:?}.", message))?
+            .clone();
+        self.state = match (self.state, message) {
+            (State::Busy, Message::NoBlocks) => {
+                self.running = false;
+                self.requested.pop_front();
+                self.result.push_back(Fetched::NoBlocks(range));
+                self.next_state()
+            }
+            (State::Busy, Message::StartBatch) => State::Streaming,
+            (State::Streaming, Message::Block(bytes)) => {
+                self.running = false;
+                self.result
+                    .push_back(Fetched::Block(range, bytes.into_boxed_slice()));
+                State::Streaming
+            }
+            (State::Streaming, Message::BatchDone) => {
+                self.requested.pop_front();
+                self.next_state()
+            }
+            (state, message) => {
+                return Err(format!(
+                    "Unexpected message {:?} in state {:?}.",
+                    message, state
+                ))
+            }
+        };
+        // Blocks received while closing are discarded.
+        if self.done {
+            self.result.clear();
+        }
+        Ok(())
+    }
+
+    fn channel<'b>(&'b mut self) -> &'b mut Channel<'a>
     where
         'a: 'b,
     {
@@ -249,11 +580,13 @@ impl<'a> Protocol<'a> for BlockFetch<'a> {
 mod tests {
     use super::*;
     use crate::mux::Connection;
+    use futures::StreamExt;
+    use std::time::Duration;
 
-    static MOCK_DATA: &'static [(u64, &[u8], &[u8])] = &[
-        (42, b"mock-hash-1", b"mock-block-1"),
-        (43, b"mock-hash-2", b"mock-block-2"),
-        (44, b"mock-hash-3", b"mock-block-3"),
+    static MOCK_DATA: &[(u64, Hash<32>, &[u8])] = &[
+        (42, Hash::new([1; 32]), b"mock-block-1"),
+        (43, Hash::new([2; 32]), b"mock-block-2"),
+        (44, Hash::new([3; 32]), b"mock-block-3"),
     ];
 
     #[test]
@@ -282,15 +615,15 @@ mod tests {
     #[tokio::test]
     async fn client_works() {
         env_logger::builder().is_test(true).try_init().ok();
-        let (mut connection, mut endpoint) = Connection::test_unix_pair().unwrap();
+        let (connection, endpoint) = Connection::test_unix_pair().unwrap();
         let mut channel = endpoint.channel(0x8003);
 
         let &(first_slot, first_hash, _) = MOCK_DATA.first().unwrap();
         let &(last_slot, last_hash, _) = MOCK_DATA.last().unwrap();
         let mut client = builder()
-            .first(first_slot, first_hash.to_vec())
-            .last(last_slot, last_hash.to_vec())
-            .client(&mut connection)
+            .first(first_slot, first_hash)
+            .last(last_slot, last_hash)
+            .client(&connection)
             .unwrap();
         assert_eq!(client.state, State::Idle);
         // Client collects a range of blocks.
@@ -298,9 +631,9 @@ mod tests {
             async {
                 let mut blocks = client.run().await.unwrap();
                 for (_, _, block) in MOCK_DATA {
-                    assert_eq!(blocks.next().await, Ok(Some(Box::from(*block))),);
+                    assert_eq!(blocks.next().await, Some(Ok(Box::from(*block))));
                 }
-                assert_eq!(blocks.next().await, Ok(None),);
+                assert_eq!(blocks.next().await, None);
             },
             async {
                 channel
@@ -327,7 +660,7 @@ mod tests {
         tokio::join!(
             async {
                 let mut blocks = client.run().await.unwrap();
-                assert_eq!(blocks.next().await, Ok(None),);
+                assert_eq!(blocks.next().await, None);
             },
             async {
                 channel
@@ -353,4 +686,155 @@ mod tests {
             },
         );
     }
+
+    #[tokio::test]
+    async fn stream_works() {
+        let (connection, endpoint) = Connection::test_unix_pair().unwrap();
+        let mut channel = endpoint.channel(0x8003);
+
+        let &(first_slot, first_hash, _) = MOCK_DATA.first().unwrap();
+        let &(last_slot, last_hash, _) = MOCK_DATA.last().unwrap();
+        let mut client = builder()
+            .first(first_slot, first_hash)
+            .last(last_slot, last_hash)
+            .client(&connection)
+            .unwrap();
+        let (received, wait) = tokio::sync::oneshot::channel();
+        tokio::join!(
+            async {
+                let mut blocks = client.run().await.unwrap();
+                // First block is delivered before the rest of the batch.
+                assert_eq!(blocks.next().await, Some(Ok(Box::from(MOCK_DATA[0].2))));
+                received.send(()).unwrap();
+                let blocks: Vec<_> = blocks.collect().await;
+                assert_eq!(
+                    blocks,
+                    vec![
+                        Ok(Box::from(MOCK_DATA[1].2)),
+                        Err("Unexpected message NoBlocks in state Streaming.".to_string()),
+                    ]
+                );
+            },
+            async {
+                channel.recv().await.unwrap();
+                channel.send(&Message::StartBatch.to_bytes()).await.unwrap();
+                let message = Message::Block(MOCK_DATA[0].2.to_vec());
+                channel.send(&message.to_bytes()).await.unwrap();
+                wait.await.unwrap();
+                let message = Message::Block(MOCK_DATA[1].2.to_vec());
+                channel.send(&message.to_bytes()).await.unwrap();
+                channel.send(&Message::NoBlocks.to_bytes()).await.unwrap();
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn read_ahead_works() {
+        let (connection, endpoint) = Connection::test_unix_pair().unwrap();
+        let mut channel = endpoint.channel(0x8003);
+        let mut other = endpoint.channel(0x8002);
+
+        let &(first_slot, first_hash, _) = MOCK_DATA.first().unwrap();
+        let &(last_slot, last_hash, _) = MOCK_DATA.last().unwrap();
+        let mut client = builder()
+            .first(first_slot, first_hash)
+            .last(last_slot, last_hash)
+            .read_ahead(1)
+            .client(&connection)
+            .unwrap();
+        let mut waiting = connection.channel(0x0002);
+        tokio::join!(
+            async {
+                let blocks = client.run().await.unwrap();
+                // Frames behind the unconsumed blocks are not read.
+                let timeout = Duration::from_millis(100);
+                assert!(tokio::time::timeout(timeout, waiting.recv()).await.is_err());
+                let blocks: Vec<_> = blocks.collect().await;
+                assert_eq!(
+                    blocks,
+                    MOCK_DATA
+                        .iter()
+                        .map(|(_, _, block)| Ok(Box::from(*block)))
+                        .collect::<Vec<_>>()
+                );
+                assert_eq!(waiting.recv().await, Ok(b"other".to_vec()));
+            },
+            async {
+                channel.recv().await.unwrap();
+                channel.send(&Message::StartBatch.to_bytes()).await.unwrap();
+                for (_, _, block) in MOCK_DATA {
+                    let message = Message::Block(block.to_vec());
+                    channel.send(&message.to_bytes()).await.unwrap();
+                }
+                other.send(b"other").await.unwrap();
+                channel.send(&Message::BatchDone.to_bytes()).await.unwrap();
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn pipeline_works() {
+        let (connection, endpoint) = Connection::test_unix_pair().unwrap();
+        let mut channel = endpoint.channel(0x8003);
+
+        let point = |index: usize| -> Point { (MOCK_DATA[index].0, MOCK_DATA[index].1).into() };
+        let ranges = [
+            Range::new(point(0), point(1)),
+            Range::new(point(2), point(2)),
+            Range::new(point(1), point(2)),
+        ];
+        let mut client = builder()
+            .first(MOCK_DATA[0].0, MOCK_DATA[0].1)
+            .last(MOCK_DATA[1].0, MOCK_DATA[1].1)
+            .depth(2)
+            .pipeline(&connection)
+            .unwrap();
+        client.push(ranges[1].clone());
+        client.push(ranges[2].clone());
+        tokio::join!(
+            async {
+                for (_, _, block) in &MOCK_DATA[..2] {
+                    assert_eq!(
+                        client.next().await,
+                        Ok(Some(Fetched::Block(ranges[0].clone(), Box::from(*block))))
+                    );
+                }
+                // Third range waits for the first batch to finish.
+                assert_eq!(client.requested().count(), 2);
+                assert_eq!(client.queued().collect::<Vec<_>>(), vec![&ranges[2]]);
+                assert_eq!(
+                    client.next().await,
+                    Ok(Some(Fetched::NoBlocks(ranges[1].clone())))
+                );
+                client.push(ranges[0].clone());
+                assert!(client.cancel(&ranges[0]));
+                assert!(!client.cancel(&ranges[0]));
+                assert_eq!(
+                    client.next().await,
+                    Ok(Some(Fetched::NoBlocks(ranges[2].clone())))
+                );
+                assert_eq!(client.next().await, Ok(None));
+                assert!(client.is_idle());
+                client.done().await.unwrap();
+            },
+            async {
+                for range in &ranges[..2] {
+                    let request = Message::RequestRange(range.first.clone(), range.last.clone());
+                    channel.expect(&request.to_bytes()).await;
+                }
+                channel.send(&Message::StartBatch.to_bytes()).await.unwrap();
+                for (_, _, block) in &MOCK_DATA[..2] {
+                    let message = Message::Block(block.to_vec());
+                    channel.send(&message.to_bytes()).await.unwrap();
+                }
+                channel.send(&Message::BatchDone.to_bytes()).await.unwrap();
+                let request =
+                    Message::RequestRange(ranges[2].first.clone(), ranges[2].last.clone());
+                channel.expect(&request.to_bytes()).await;
+                channel.send(&Message::NoBlocks.to_bytes()).await.unwrap();
+                channel.send(&Message::NoBlocks.to_bytes()).await.unwrap();
+                channel.expect(&Message::ClientDone.to_bytes()).await;
+            },
+        );
+    }
 }
@@ -13,7 +13,12 @@
 
 use crate::protocols::Message as MessageOps;
 use crate::{
+    chain::exponential_offsets,
+    chain::ChainFragment,
+    chain::Checkpoints,
+    chain::SECURITY_PARAM,
     model::BlockHeader,
+    model::Hash,
     model::Point,
     model::Tip,
     mux::Channel,
@@ -26,7 +31,24 @@ use crate::{
     protocols::WrappedBlockHeader,
     Error,
 };
+use futures::{
+    Future,
+    Stream,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
 use serde_cbor::Value;
+use std::{
+    collections::VecDeque,
+    fmt,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
 
 #[derive(Debug, Clone, Copy)]
 pub enum State {
@@ -80,7 +102,10 @@ impl MessageOps for Message {
             Message::AwaitReply => vec![Value::Integer(1)],
             Message::RollForward(header, tip) => vec![
                 Value::Integer(2),
-                Value::Array(vec![Value::Integer(0), Value::Bytes(header.bytes.clone())]),
+                Value::Array(vec![
+                    Value::Integer(header.era.into()),
+                    Value::Bytes(header.bytes.clone()),
+                ]),
                 Value::Array(tip_to_vec(tip)),
             ],
             Message::RollBackward(point, tip) => vec![
@@ -93,12 +118,7 @@ impl MessageOps for Message {
                 Value::Array(
                     points
                         .iter()
-                        .map(|Point { slot, hash }| {
-                            Value::Array(vec![
-                                Value::Integer(*slot as i128),
-                                Value::Bytes(hash.clone()),
-                            ])
-                        })
+                        .map(|point| Value::Array(point_to_vec(point)))
                         .collect(),
                 ),
             ],
@@ -123,38 +143,131 @@ impl MessageOps for Message {
 }
 
 pub fn builder() -> ChainSyncBuilder {
-    ChainSyncBuilder {}
+    ChainSyncBuilder {
+        security_param: SECURITY_PARAM,
+    }
 }
 
-pub struct ChainSyncBuilder {}
+pub struct ChainSyncBuilder {
+    security_param: u64,
+}
 
 impl ChainSyncBuilder {
-    pub fn client<'a>(self, connection: &'a mut Connection) -> ChainSync<'a> {
+    // Maximum rollback depth `k` accepted by the follower.
+    pub fn security_param(&mut self, k: u64) -> &mut Self {
+        self.security_param = k;
+        self
+    }
+
+    pub fn client<'a>(&self, connection: &'a Connection) -> ChainSync<'a> {
         ChainSync {
             channel: connection.channel(0x0002),
             intersect: None,
             reply: None,
             state: State::Idle,
             query: None,
+            progress: Progress::default(),
+        }
+    }
+
+    pub fn checked<'a>(&self, connection: &'a Connection) -> Checked<'a> {
+        Checked {
+            consistency: Consistency::new(self.security_param),
+            chainsync: self.client(connection),
+        }
+    }
+
+    // Client passing on headers only after `confirmations` further blocks.
+    pub fn confirmed<'a>(&self, connection: &'a Connection, confirmations: usize) -> Confirmed<'a> {
+        Confirmed {
+            chainsync: self.client(connection),
+            buffer: RollbackBuffer::new(confirmations),
+            ready: VecDeque::new(),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum Intersect {
     Found(Point, Tip),
     NotFound(Tip),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
 pub enum Reply {
     Forward(BlockHeader, Tip),
     Backward(Point, Tip),
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fork {
+    // Most recent point shared with the peer.
+    pub point: Point,
+    // Number of our blocks after the fork point.
+    pub depth: u64,
+    pub tip: Tip,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
+pub enum ChainEvent {
+    RollForward(BlockHeader, Tip),
+    RollBackward(Point, Tip),
+    // Server answered `MsgAwaitReply`, i.e. we are at its tip.
+    AtTip,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    // Our position, unknown before the first intersection or reply.
+    pub point: Option<Point>,
+    // Only known after a header has been received.
+    pub block_number: Option<i64>,
+    // Server tip as of the last message.
+    pub tip: Option<Tip>,
+    // Server answered `MsgAwaitReply` or the last reply was its tip.
+    pub at_tip: bool,
+}
+
+impl Progress {
+    pub fn slot(&self) -> Option<u64> {
+        self.point.as_ref().and_then(Point::slot)
+    }
+
+    pub fn tip_slot(&self) -> Option<u64> {
+        self.tip.as_ref().and_then(|tip| tip.point.slot())
+    }
+
+    pub fn tip_block_number(&self) -> Option<i64> {
+        self.tip.as_ref().map(|tip| tip.block_number)
+    }
+
+    pub fn slots_behind(&self) -> Option<u64> {
+        let tip_slot = self.tip_slot()?;
+        Some(tip_slot.saturating_sub(self.slot().unwrap_or(0)))
+    }
+
+    pub fn blocks_behind(&self) -> Option<i64> {
+        Some(self.tip_block_number()? - self.block_number?)
+    }
+
+    fn update(&mut self, point: Option<Point>, block_number: Option<i64>, tip: Tip) {
+        if let Some(point) = point {
+            self.at_tip = point == tip.point;
+            self.point = Some(point);
+            self.block_number = block_number;
+        }
+        self.tip = Some(tip);
+    }
+}
+
 enum Query {
     Intersect(Vec<Point>),
     Reply,
+    // Like `Reply` but returns early on `MsgAwaitReply`.
+    Event,
+    Done,
 }
 
 pub struct ChainSync<'a> {
@@ -163,6 +276,7 @@ pub struct ChainSync<'a> {
     intersect: Option<Intersect>,
     reply: Option<Reply>,
     state: State,
+    progress: Progress,
 }
 
 impl<'a> ChainSync<'a> {
@@ -172,11 +286,154 @@ impl<'a> ChainSync<'a> {
         Ok(self.intersect.take().unwrap())
     }
 
+    // Intersect at the newest checkpoint known to the peer, trying
+    // exponentially spaced batches of older checkpoints and finally Origin.
+    pub async fn resume<C: Checkpoints>(&mut self, checkpoints: &C) -> Result<Intersect, Error> {
+        let points = checkpoints.load()?;
+        let mut start = 0;
+        while start < points.len() {
+            let offsets = exponential_offsets(points.len() - start);
+            let batch = offsets
+                .iter()
+                .map(|offset| points[start + offset].clone())
+                .collect();
+            if let Intersect::Found(point, tip) = self.find_intersect(batch).await? {
+                return Ok(Intersect::Found(point, tip));
+            }
+            start += offsets.last().unwrap() + 1;
+        }
+        self.find_intersect(vec![Point::Origin]).await
+    }
+
+    // Binary search `chain`, oldest first, for the most recent point that is
+    // also on the peer's chain. The client is left positioned at that point.
+    pub async fn find_fork(&mut self, chain: &[Point]) -> Result<Fork, Error> {
+        // Points before `low` are shared, points from `high` on are not.
+        let (mut low, mut high) = (0, chain.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.find_intersect(vec![chain[middle].clone()]).await? {
+                Intersect::Found(..) => low = middle + 1,
+                Intersect::NotFound(_) => high = middle,
+            }
+        }
+        let point = match low {
+            0 => Point::Origin,
+            low => chain[low - 1].clone(),
+        };
+        match self.find_intersect(vec![point]).await? {
+            Intersect::Found(point, tip) => Ok(Fork {
+                point,
+                depth: (chain.len() - low) as u64,
+                tip,
+            }),
+            Intersect::NotFound(_) => Err("Peer switched chains during fork search.".to_string()),
+        }
+    }
+
+    // Next block or rollback. At the server's tip this waits through
+    // `MsgAwaitReply` until a new block arrives, `progress().at_tip` tells
+    // whether the tip has been reached.
     pub async fn request_next(&mut self) -> Result<Reply, Error> {
         self.query = Some(Query::Reply);
         self.execute().await?;
         Ok(self.reply.take().unwrap())
     }
+
+    // Like `request_next` but reports when the server has no newer block.
+    pub async fn next_event(&mut self) -> Result<ChainEvent, Error> {
+        self.query = Some(Query::Event);
+        self.execute().await?;
+        Ok(match self.reply.take() {
+            Some(Reply::Forward(header, tip)) => ChainEvent::RollForward(header, tip),
+            Some(Reply::Backward(point, tip)) => ChainEvent::RollBackward(point, tip),
+            None => ChainEvent::AtTip,
+        })
+    }
+
+    // Send `MsgDone`, an outstanding reply is awaited and discarded first.
+    pub async fn done(&mut self) -> Result<(), Error> {
+        match self.state {
+            State::CanAwait | State::MustReply => {
+                self.request_next().await?;
+            }
+            State::Done => return Err("ChainSync already done.".to_string()),
+            _ => (),
+        }
+        self.query = Some(Query::Done);
+        self.execute().await
+    }
+
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+
+    pub fn into_stream(self) -> ChainSyncStream<'a> {
+        ChainSyncStream {
+            chainsync: Some(self),
+            pending: None,
+        }
+    }
+}
+
+type PendingEvent<'a> =
+    Pin<Box<dyn Future<Output = (ChainSync<'a>, Result<ChainEvent, Error>)> + Send + 'a>>;
+
+// Dropping the stream queues `MsgDone`, sent with the next frame on the
+// connection, unless a request is in progress. Use `done` to wait for it.
+pub struct ChainSyncStream<'a> {
+    chainsync: Option<ChainSync<'a>>,
+    pending: Option<PendingEvent<'a>>,
+}
+
+impl<'a> ChainSyncStream<'a> {
+    // Finish the request in progress, if any, and send `MsgDone`.
+    pub async fn done(mut self) -> Result<(), Error> {
+        let mut chainsync = match self.pending.take() {
+            Some(pending) => pending.await.0,
+            None => self.chainsync.take().ok_or("Stream already finished.")?,
+        };
+        chainsync.done().await
+    }
+}
+
+impl Drop for ChainSyncStream<'_> {
+    fn drop(&mut self) {
+        if let Some(chainsync) = &mut self.chainsync {
+            if matches!(chainsync.state, State::Idle) {
+                chainsync.state = State::Done;
+                chainsync.channel.queue(&Message::Done.to_bytes());
+            }
+        }
+    }
+}
+
+impl<'a> Stream for ChainSyncStream<'a> {
+    type Item = Result<ChainEvent, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.pending.is_none() {
+            let mut chainsync = match self.chainsync.take() {
+                Some(chainsync) => chainsync,
+                None => return Poll::Ready(None),
+            };
+            self.pending = Some(Box::pin(async move {
+                let event = chainsync.next_event().await;
+                (chainsync, event)
+            }));
+        }
+        match self.pending.as_mut().unwrap().as_mut().poll(cx) {
+            Poll::Ready((chainsync, event)) => {
+                self.pending = None;
+                // Stream ends on the first error.
+                if event.is_ok() {
+                    self.chainsync = Some(chainsync);
+                }
+                Poll::Ready(Some(event))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
 }
 
 impl<'a> Protocol<'a> for ChainSync<'a> {
@@ -195,13 +452,13 @@ impl<'a> Protocol<'a> for ChainSync<'a> {
         if self.query.is_none() {
             return Agency::None;
         }
-        return match self.state {
+        match self.state {
             State::Idle => Agency::Client,
             State::Intersect => Agency::Server,
             State::CanAwait => Agency::Server,
             State::MustReply => Agency::Server,
             State::Done => Agency::None,
-        };
+        }
     }
 
     fn state(&self) -> Self::State {
@@ -215,10 +472,14 @@ impl<'a> Protocol<'a> for ChainSync<'a> {
                     self.state = State::Intersect;
                     Ok(Message::FindIntersect(points.clone()))
                 }
-                Query::Reply => {
+                Query::Reply | Query::Event => {
                     self.state = State::CanAwait;
                     Ok(Message::RequestNext)
                 }
+                Query::Done => {
+                    self.state = State::Done;
+                    Ok(Message::Done)
+                }
             },
             other => Err(format!("Unsupported: {:?}", other)),
         }
@@ -231,23 +492,34 @@ impl<'a> Protocol<'a> for ChainSync<'a> {
             }
             Message::AwaitReply => {
                 self.state = State::MustReply;
+                self.progress.at_tip = true;
+                if let Some(Query::Event) = self.query {
+                    self.query = None;
+                }
             }
             Message::RollForward(header, tip) => {
-                self.reply = Some(Reply::Forward(header.try_into()?, tip));
+                let header: BlockHeader = header.try_into()?;
+                let point = Some(header.point());
+                self.progress
+                    .update(point, Some(header.block_number), tip.clone());
+                self.reply = Some(Reply::Forward(header, tip));
                 self.query = None;
                 self.state = State::Idle;
             }
             Message::RollBackward(point, tip) => {
+                self.progress.update(Some(point.clone()), None, tip.clone());
                 self.reply = Some(Reply::Backward(point, tip));
                 self.query = None;
                 self.state = State::Idle;
             }
             Message::IntersectFound(point, tip) => {
+                self.progress.update(Some(point.clone()), None, tip.clone());
                 self.intersect = Some(Intersect::Found(point, tip));
                 self.query = None;
                 self.state = State::Idle;
             }
             Message::IntersectNotFound(tip) => {
+                self.progress.update(None, None, tip.clone());
                 self.intersect = Some(Intersect::NotFound(tip));
                 self.query = None;
                 self.state = State::Idle;
@@ -260,29 +532,268 @@ impl<'a> Protocol<'a> for ChainSync<'a> {
         Ok(())
     }
 
-    fn channel<'b>(&'b mut self) -> &mut Channel<'a>
+    fn channel<'b>(&'b mut self) -> &'b mut Channel<'a>
     where
         'a: 'b,
     {
         &mut self.channel
     }
 }
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConsistencyError {
+    ChainSync(Error),
+    PrevHashMismatch { expected: Hash<32>, found: Hash<32> },
+    SlotNotIncreasing { previous: u64, found: u64 },
+    BlockNumberMismatch { expected: i64, found: i64 },
+    // Rollback to a point that is not among the last k blocks.
+    RollbackTooDeep(Point),
+}
+
+impl fmt::Display for ConsistencyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConsistencyError::ChainSync(error) => write!(f, "ChainSync failed: {}", error),
+            ConsistencyError::PrevHashMismatch { expected, found } => {
+                write!(f, "Previous hash {} expected, found {}.", expected, found)
+            }
+            ConsistencyError::SlotNotIncreasing { previous, found } => {
+                write!(f, "Slot {} does not follow slot {}.", found, previous)
+            }
+            ConsistencyError::BlockNumberMismatch { expected, found } => {
+                write!(f, "Block number {} expected, found {}.", expected, found)
+            }
+            ConsistencyError::RollbackTooDeep(point) => {
+                write!(f, "Rollback to {:?} exceeds the security parameter.", point)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConsistencyError {}
+
+impl From<ConsistencyError> for Error {
+    fn from(error: ConsistencyError) -> Error {
+        error.to_string()
+    }
+}
+
+//
+// ChainSync client checking that the upstream peer delivers a consistent
+// chain of headers. `follower::ChainFollower` also fetches the blocks.
+//
+pub struct Checked<'a> {
+    chainsync: ChainSync<'a>,
+    consistency: Consistency,
+}
+
+impl<'a> Checked<'a> {
+    pub async fn find_intersect(&mut self, points: Vec<Point>) -> Result<Intersect, Error> {
+        let intersect = self.chainsync.find_intersect(points).await?;
+        if let Intersect::Found(point, _) = &intersect {
+            self.consistency.reset(point.clone());
+        }
+        Ok(intersect)
+    }
+
+    pub async fn resume<C: Checkpoints>(&mut self, checkpoints: &C) -> Result<Intersect, Error> {
+        let intersect = self.chainsync.resume(checkpoints).await?;
+        if let Intersect::Found(point, _) = &intersect {
+            self.consistency.reset(point.clone());
+        }
+        Ok(intersect)
+    }
+
+    pub async fn request_next(&mut self) -> Result<Reply, ConsistencyError> {
+        let reply = self
+            .chainsync
+            .request_next()
+            .await
+            .map_err(ConsistencyError::ChainSync)?;
+        self.consistency.apply(&reply)?;
+        Ok(reply)
+    }
+
+    pub async fn done(&mut self) -> Result<(), Error> {
+        self.chainsync.done().await
+    }
+}
+
+//
+// Holds items, e.g. headers or fetched blocks, until they are followed by
+// the configured number of blocks.
+//
+pub struct RollbackBuffer<T> {
+    items: ChainFragment<T>,
+}
+
+impl<T> RollbackBuffer<T> {
+    pub fn new(confirmations: usize) -> Self {
+        RollbackBuffer {
+            items: ChainFragment::with_security_param(Point::Origin, confirmations as u64),
+        }
+    }
+
+    // Drop buffered items and continue from `point`, e.g. an intersection.
+    pub fn reset(&mut self, point: Point) {
+        self.items.reset(point);
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    // Buffer `item` and return items that got enough confirmations.
+    pub fn roll_forward(&mut self, point: Point, item: T) -> Vec<T> {
+        self.items
+            .push(point, item)
+            .into_iter()
+            .map(|(_, item)| item)
+            .collect()
+    }
+
+    // Drop items after `point` and return them, newest first.
+    pub fn roll_backward(&mut self, point: &Point) -> Result<Vec<T>, Error> {
+        Ok(self
+            .items
+            .truncate(point)?
+            .into_iter()
+            .map(|(_, item)| item)
+            .collect())
+    }
+}
+
+pub struct Confirmed<'a> {
+    chainsync: ChainSync<'a>,
+    buffer: RollbackBuffer<BlockHeader>,
+    ready: VecDeque<BlockHeader>,
+}
+
+impl<'a> Confirmed<'a> {
+    pub async fn find_intersect(&mut self, points: Vec<Point>) -> Result<Intersect, Error> {
+        let intersect = self.chainsync.find_intersect(points).await?;
+        if let Intersect::Found(point, _) = &intersect {
+            self.buffer.reset(point.clone());
+            self.ready.clear();
+        }
+        Ok(intersect)
+    }
+
+    pub async fn resume<C: Checkpoints>(&mut self, checkpoints: &C) -> Result<Intersect, Error> {
+        let intersect = self.chainsync.resume(checkpoints).await?;
+        if let Intersect::Found(point, _) = &intersect {
+            self.buffer.reset(point.clone());
+            self.ready.clear();
+        }
+        Ok(intersect)
+    }
+
+    // Next header with enough confirmations, rollbacks are handled internally.
+    pub async fn next(&mut self) -> Result<BlockHeader, Error> {
+        loop {
+            if let Some(header) = self.ready.pop_front() {
+                return Ok(header);
+            }
+            match self.chainsync.request_next().await? {
+                Reply::Forward(header, _) => {
+                    let confirmed = self.buffer.roll_forward(header.point(), header);
+                    self.ready.extend(confirmed);
+                }
+                Reply::Backward(point, _) => {
+                    self.buffer.roll_backward(&point)?;
+                }
+            }
+        }
+    }
+
+    pub async fn done(&mut self) -> Result<(), Error> {
+        self.chainsync.done().await
+    }
+}
+
+// Block numbers of the last k blocks, unknown for the intersection.
+struct Consistency {
+    blocks: ChainFragment<i64>,
+    // Block number of the anchor.
+    anchor: Option<i64>,
+}
+
+impl Consistency {
+    fn new(k: u64) -> Self {
+        Consistency {
+            blocks: ChainFragment::with_security_param(Point::Origin, k),
+            anchor: None,
+        }
+    }
+
+    fn reset(&mut self, point: Point) {
+        self.blocks.reset(point);
+        self.anchor = None;
+    }
+
+    fn apply(&mut self, reply: &Reply) -> Result<(), ConsistencyError> {
+        match reply {
+            Reply::Forward(header, _) => {
+                let slot = header.slot_number as u64;
+                if let Point::Specific(previous, hash) = self.blocks.tip() {
+                    if hash != header.prev_hash {
+                        return Err(ConsistencyError::PrevHashMismatch {
+                            expected: hash,
+                            found: header.prev_hash,
+                        });
+                    }
+                    if slot <= previous {
+                        return Err(ConsistencyError::SlotNotIncreasing {
+                            previous,
+                            found: slot,
+                        });
+                    }
+                }
+                let block_number = self.blocks.items().next_back().copied().or(self.anchor);
+                if let Some(block_number) = block_number {
+                    if header.block_number != block_number + 1 {
+                        return Err(ConsistencyError::BlockNumberMismatch {
+                            expected: block_number + 1,
+                            found: header.block_number,
+                        });
+                    }
+                }
+                let immutable = self
+                    .blocks
+                    .push(Point::Specific(slot, header.hash), header.block_number);
+                if let Some((_, block_number)) = immutable.last() {
+                    self.anchor = Some(*block_number);
+                }
+            }
+            Reply::Backward(point, _) => {
+                self.blocks
+                    .truncate(point)
+                    .map_err(|_| ConsistencyError::RollbackTooDeep(point.clone()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::model::mock_header;
+    use futures::StreamExt;
 
     #[test]
     fn message_cbor_works() {
-        let point = Point {
-            slot: 0x1234,
-            hash: b"mock-point-hash".to_vec(),
-        };
+        let point = Point::Specific(0x1234, Hash::new([1; 32]));
         let tip = Tip {
-            slot_number: 0x5678,
-            hash: b"mock-tip-hash".to_vec(),
+            point: Point::Specific(0x5678, Hash::new([2; 32])),
             block_number: 0xabcd,
         };
         let header = WrappedBlockHeader {
+            era: 1,
             bytes: b"mock-block-header".to_vec(),
         };
         let messages = [
@@ -290,7 +801,8 @@ mod tests {
             Message::AwaitReply,
             Message::RollForward(header, tip.clone()),
             Message::RollBackward(point.clone(), tip.clone()),
-            Message::FindIntersect(vec![point.clone(), point.clone()]),
+            Message::FindIntersect(vec![point.clone(), Point::Origin]),
+            Message::IntersectFound(Point::Origin, tip.clone()),
             Message::IntersectFound(point.clone(), tip.clone()),
             Message::IntersectNotFound(tip.clone()),
             Message::Done,
@@ -302,4 +814,324 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn reply_json_works() {
+        let tip = Tip {
+            point: Point::Specific(0x5678, Hash::new([2; 32])),
+            block_number: 0xabcd,
+        };
+        let reply = Reply::Backward(Point::Origin, tip);
+        let json = serde_json::to_string(&reply).unwrap();
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"Backward":["Origin",{{"point":{{"Specific":[22136,"{}"]}},"block_number":43981}}]}}"#,
+                "02".repeat(32),
+            ),
+        );
+        assert_eq!(serde_json::from_str::<Reply>(&json).unwrap(), reply);
+    }
+
+    #[test]
+    fn consistency_works() {
+        let tip = Tip {
+            point: Point::Origin,
+            block_number: 0,
+        };
+        let forward = |header: BlockHeader| Reply::Forward(header, tip.clone());
+        let mut consistency = Consistency::new(2);
+        let intersect = Point::Specific(10, Hash::new([1; 32]));
+        consistency.reset(intersect.clone());
+        assert_eq!(
+            consistency.apply(&Reply::Backward(intersect.clone(), tip.clone())),
+            Ok(())
+        );
+        for (block_number, slot) in [(2, 20), (3, 30), (4, 40)] {
+            let prev_hash = Hash::new([block_number as u8 - 1; 32]);
+            let header = mock_header(block_number, slot, prev_hash);
+            assert_eq!(consistency.apply(&forward(header)), Ok(()));
+        }
+        assert_eq!(
+            consistency.apply(&forward(mock_header(5, 50, Hash::new([3; 32])))),
+            Err(ConsistencyError::PrevHashMismatch {
+                expected: Hash::new([4; 32]),
+                found: Hash::new([3; 32]),
+            })
+        );
+        assert_eq!(
+            consistency.apply(&forward(mock_header(5, 40, Hash::new([4; 32])))),
+            Err(ConsistencyError::SlotNotIncreasing {
+                previous: 40,
+                found: 40,
+            })
+        );
+        assert_eq!(
+            consistency.apply(&forward(mock_header(6, 50, Hash::new([4; 32])))),
+            Err(ConsistencyError::BlockNumberMismatch {
+                expected: 5,
+                found: 6,
+            })
+        );
+        // Only the last k blocks may be rolled back.
+        assert_eq!(
+            consistency.apply(&Reply::Backward(intersect.clone(), tip.clone())),
+            Err(ConsistencyError::RollbackTooDeep(intersect))
+        );
+        let point = Point::Specific(20, Hash::new([2; 32]));
+        assert_eq!(
+            consistency.apply(&Reply::Backward(point, tip.clone())),
+            Ok(())
+        );
+        let header = mock_header(3, 25, Hash::new([2; 32]));
+        assert_eq!(consistency.apply(&forward(header)), Ok(()));
+    }
+
+    #[test]
+    fn rollback_buffer_works() {
+        let point = |slot| Point::Specific(slot, Hash::new([slot as u8; 32]));
+        let mut buffer = RollbackBuffer::<u32>::new(2);
+        buffer.reset(point(0));
+        assert!(buffer.roll_forward(point(1), 1).is_empty());
+        assert!(buffer.roll_forward(point(2), 2).is_empty());
+        assert_eq!(buffer.roll_forward(point(3), 3), vec![1]);
+        assert_eq!(buffer.roll_backward(&point(2)), Ok(vec![3]));
+        assert!(buffer.roll_forward(point(4), 4).is_empty());
+        assert_eq!(buffer.roll_forward(point(5), 5), vec![2]);
+        // Confirmed items cannot be rolled back.
+        assert!(buffer.roll_backward(&point(1)).is_err());
+        assert_eq!(buffer.roll_backward(&point(2)), Ok(vec![5, 4]));
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.roll_backward(&point(2)), Ok(vec![]));
+    }
+
+    #[tokio::test]
+    async fn resume_works() {
+        let (connection, endpoint) = Connection::test_unix_pair().unwrap();
+        let mut channel = endpoint.channel(0x8002);
+        let point = |slot| Point::Specific(slot, Hash::new([slot as u8; 32]));
+        let tip = Tip {
+            point: point(100),
+            block_number: 100,
+        };
+        let checkpoints: Vec<Point> = (0..10).rev().map(point).collect();
+        let mut chainsync = builder().client(&connection);
+        tokio::join!(
+            async {
+                assert_eq!(
+                    chainsync.resume(&checkpoints).await,
+                    Ok(Intersect::Found(Point::Origin, tip.clone()))
+                );
+            },
+            async {
+                for points in [
+                    vec![point(9), point(8), point(6), point(2)],
+                    vec![point(1), point(0)],
+                ] {
+                    channel
+                        .expect(&Message::FindIntersect(points).to_bytes())
+                        .await;
+                    let reply = Message::IntersectNotFound(tip.clone());
+                    channel.send(&reply.to_bytes()).await.unwrap();
+                }
+                channel
+                    .expect(&Message::FindIntersect(vec![Point::Origin]).to_bytes())
+                    .await;
+                let reply = Message::IntersectFound(Point::Origin, tip.clone());
+                channel.send(&reply.to_bytes()).await.unwrap();
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn find_fork_works() {
+        let (connection, endpoint) = Connection::test_unix_pair().unwrap();
+        let mut channel = endpoint.channel(0x8002);
+        let point = |slot| Point::Specific(slot, Hash::new([slot as u8; 32]));
+        let tip = Tip {
+            point: point(100),
+            block_number: 100,
+        };
+        let mut chainsync = builder().client(&connection);
+        for (shared, chain_length) in [(6, 10), (0, 10), (10, 10), (0, 0)] {
+            let chain: Vec<Point> = (0..chain_length).map(point).collect();
+            let peer = async {
+                let mut queries = 0;
+                loop {
+                    let bytes = channel.recv().await.unwrap();
+                    let values: Vec<Value> = serde_cbor::from_slice(&bytes).unwrap();
+                    let reply = match Message::from_iter(Values::from_vec(&values)).unwrap() {
+                        Message::FindIntersect(points) => match &points[..] {
+                            [Point::Origin] => Message::IntersectFound(Point::Origin, tip.clone()),
+                            [point] if point.slot() < Some(shared) => {
+                                Message::IntersectFound(point.clone(), tip.clone())
+                            }
+                            _ => Message::IntersectNotFound(tip.clone()),
+                        },
+                        other => panic!("Unexpected message {:?}", other),
+                    };
+                    channel.send(&reply.to_bytes()).await.unwrap();
+                    queries += 1;
+                    assert!(queries <= 5);
+                }
+            };
+            let expected = Fork {
+                point: match shared {
+                    0 => Point::Origin,
+                    shared => point(shared - 1),
+                },
+                depth: chain_length - shared,
+                tip: tip.clone(),
+            };
+            tokio::select! {
+                fork = chainsync.find_fork(&chain) => assert_eq!(fork, Ok(expected)),
+                _ = peer => unreachable!(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_works() {
+        let (connection, endpoint) = Connection::test_unix_pair().unwrap();
+        let mut channel = endpoint.channel(0x8002);
+        let tip = Tip {
+            point: Point::Specific(10, Hash::new([1; 32])),
+            block_number: 1,
+        };
+        let mut stream = builder().client(&connection).into_stream();
+        tokio::join!(
+            async {
+                match stream.next().await {
+                    Some(Ok(ChainEvent::RollBackward(Point::Origin, _))) => (),
+                    other => panic!("Unexpected event {:?}", other),
+                }
+                match stream.next().await {
+                    Some(Ok(ChainEvent::RollForward(header, _))) => {
+                        assert_eq!(header.block_number, 1)
+                    }
+                    other => panic!("Unexpected event {:?}", other),
+                }
+                assert_eq!(stream.next().await, Some(Ok(ChainEvent::AtTip)));
+                // Waits for the reply following `MsgAwaitReply`.
+                match stream.next().await {
+                    Some(Ok(ChainEvent::RollForward(header, _))) => {
+                        assert_eq!(header.block_number, 2)
+                    }
+                    other => panic!("Unexpected event {:?}", other),
+                }
+                assert_eq!(stream.next().await, Some(Ok(ChainEvent::AtTip)));
+                // Done waits for the outstanding reply.
+                stream.done().await.unwrap();
+            },
+            async {
+                let request = Message::RequestNext.to_bytes();
+                channel.expect(&request).await;
+                let reply = Message::RollBackward(Point::Origin, tip.clone());
+                channel.send(&reply.to_bytes()).await.unwrap();
+                channel.expect(&request).await;
+                let header = mock_header(1, 10, Hash::new([0; 32]));
+                let reply = Message::RollForward(header.try_into().unwrap(), tip.clone());
+                channel.send(&reply.to_bytes()).await.unwrap();
+                channel.expect(&request).await;
+                channel.send(&Message::AwaitReply.to_bytes()).await.unwrap();
+                let header = mock_header(2, 20, Hash::new([1; 32]));
+                let reply = Message::RollForward(header.try_into().unwrap(), tip.clone());
+                channel.send(&reply.to_bytes()).await.unwrap();
+                channel.expect(&request).await;
+                channel.send(&Message::AwaitReply.to_bytes()).await.unwrap();
+                let reply = Message::RollBackward(Point::Origin, tip.clone());
+                channel.send(&reply.to_bytes()).await.unwrap();
+                channel.expect(&Message::Done.to_bytes()).await;
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn done_works() {
+        let (connection, endpoint) = Connection::test_unix_pair().unwrap();
+        let mut channel = endpoint.channel(0x8002);
+        // Dropping a client sends nothing.
+        drop(builder().client(&connection));
+        let mut chainsync = builder().client(&connection);
+        chainsync.done().await.unwrap();
+        assert!(matches!(chainsync.state(), State::Done));
+        channel.expect(&Message::Done.to_bytes()).await;
+        assert!(chainsync.done().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stream_drop_sends_done() {
+        let (connection, endpoint) = Connection::test_unix_pair().unwrap();
+        let mut channel = endpoint.channel(0x8002);
+        let mut other = endpoint.channel(0x8003);
+        drop(builder().client(&connection).into_stream());
+        // Queued until the next frame on the connection.
+        connection.channel(0x0003).send(b"next").await.unwrap();
+        channel.expect(&Message::Done.to_bytes()).await;
+        other.expect(b"next").await;
+    }
+
+    #[tokio::test]
+    async fn progress_works() {
+        let (connection, endpoint) = Connection::test_unix_pair().unwrap();
+        let mut channel = endpoint.channel(0x8002);
+        // Headers as decoded from the wire, with the hash of their bytes.
+        let header = |block_number| -> BlockHeader {
+            let header = mock_header(block_number, block_number * 10, Hash::new([0; 32]));
+            WrappedBlockHeader::try_from(header)
+                .unwrap()
+                .try_into()
+                .unwrap()
+        };
+        let tip = Tip {
+            point: header(3).point(),
+            block_number: 3,
+        };
+        let mut chainsync = builder().client(&connection);
+        tokio::join!(
+            async {
+                chainsync.find_intersect(vec![Point::Origin]).await.unwrap();
+                let progress = chainsync.progress();
+                assert_eq!(progress.slots_behind(), Some(30));
+                assert_eq!(progress.blocks_behind(), None);
+                assert!(!progress.at_tip);
+                // Every block is returned, the last one after `MsgAwaitReply`.
+                for block_number in 1..=4 {
+                    match chainsync.request_next().await {
+                        Ok(Reply::Forward(header, _)) => {
+                            assert_eq!(header.block_number, block_number)
+                        }
+                        other => panic!("Unexpected reply {:?}", other),
+                    }
+                    assert_eq!(chainsync.progress().at_tip, block_number >= 3);
+                }
+                let progress = chainsync.progress();
+                assert_eq!(progress.slot(), Some(40));
+                assert_eq!(progress.tip_block_number(), Some(4));
+                assert_eq!(progress.blocks_behind(), Some(0));
+                assert!(progress.at_tip);
+            },
+            async {
+                channel
+                    .expect(&Message::FindIntersect(vec![Point::Origin]).to_bytes())
+                    .await;
+                let reply = Message::IntersectFound(Point::Origin, tip.clone());
+                channel.send(&reply.to_bytes()).await.unwrap();
+                for block_number in 1..=3 {
+                    channel.expect(&Message::RequestNext.to_bytes()).await;
+                    let header = header(block_number).try_into().unwrap();
+                    let reply = Message::RollForward(header, tip.clone());
+                    channel.send(&reply.to_bytes()).await.unwrap();
+                }
+                channel.expect(&Message::RequestNext.to_bytes()).await;
+                channel.send(&Message::AwaitReply.to_bytes()).await.unwrap();
+                let tip = Tip {
+                    point: header(4).point(),
+                    block_number: 4,
+                };
+                let reply = Message::RollForward(header(4).try_into().unwrap(), tip);
+                channel.send(&reply.to_bytes()).await.unwrap();
+            },
+        );
+    }
 }
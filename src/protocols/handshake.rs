@@ -26,11 +26,19 @@ use log::{
     debug,
     error,
 };
+use serde::{
+    Deserialize,
+    Serialize,
+};
 use serde_cbor::{
     Value,
     Value::*,
 };
-use std::convert::TryFrom;
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum State {
@@ -41,37 +49,140 @@ pub enum State {
 
 #[derive(Debug, PartialEq)]
 pub enum Message {
-    ProposeVersions(Vec<(Version, u32)>),
-    AcceptVersion(Version, u32),
-    Refuse,
+    ProposeVersions(Vec<Version>),
+    AcceptVersion(Version),
+    Refuse(RefuseReason),
+    // The responder's versions, sent instead of accepting when queried.
+    QueryReply(Vec<Version>),
 }
 
-impl MessageOps for Message {
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RefuseReason {
+    // Versions supported by the refusing side.
+    VersionMismatch(Vec<u16>),
+    HandshakeDecodeError(u16, String),
+    Refused(u16, String),
+}
+
+impl RefuseReason {
     fn from_iter(mut array: Values) -> Result<Self, Error> {
-        match array.integer()? {
+        let reason = match array.integer()? {
             0 => {
-                let versions: Vec<(_, _)> = array
-                    .map()?
-                    .iter()
-                    .map(|(key, value)| Version::from_values(key.clone(), value.clone()))
-                    .collect::<Result<Vec<_>, _>>()?;
-                Ok(Message::ProposeVersions(versions))
+                let versions = array
+                    .array()?
+                    .into_vec()
+                    .into_iter()
+                    .map(|version| match version {
+                        Value::Integer(version) => version_number(version),
+                        other => Err(format!("Version number required: {:?}", other)),
+                    })
+                    .collect::<Result<_, _>>()?;
+                RefuseReason::VersionMismatch(versions)
             }
+            1 => RefuseReason::HandshakeDecodeError(
+                version_number(array.integer()?)?,
+                array.text()?.clone(),
+            ),
+            2 => RefuseReason::Refused(version_number(array.integer()?)?, array.text()?.clone()),
+            other => return Err(format!("Unexpected refuse reason: {}.", other)),
+        };
+        array.end()?;
+        Ok(reason)
+    }
+
+    fn to_values(&self) -> Vec<Value> {
+        match self {
+            RefuseReason::VersionMismatch(versions) => vec![
+                Value::Integer(0),
+                Array(
+                    versions
+                        .iter()
+                        .map(|version| Value::Integer((*version).into()))
+                        .collect(),
+                ),
+            ],
+            RefuseReason::HandshakeDecodeError(version, message) => vec![
+                Value::Integer(1),
+                Value::Integer((*version).into()),
+                Value::Text(message.clone()),
+            ],
+            RefuseReason::Refused(version, message) => vec![
+                Value::Integer(2),
+                Value::Integer((*version).into()),
+                Value::Text(message.clone()),
+            ],
+        }
+    }
+}
+
+impl fmt::Display for RefuseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RefuseReason::VersionMismatch(versions) => {
+                write!(f, "Version mismatch, supported versions: {:?}", versions)
+            }
+            RefuseReason::HandshakeDecodeError(version, message) => {
+                write!(f, "Version {} could not be decoded: {}", version, message)
+            }
+            RefuseReason::Refused(version, message) => {
+                write!(f, "Version {} refused: {}", version, message)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandshakeError {
+    Failed(Error),
+    // Refused by the peer or, on the server side, by us.
+    Refused(RefuseReason),
+    // The peer is on another network.
+    NetworkMagicMismatch { expected: u32, received: u32 },
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandshakeError::Failed(error) => write!(f, "Handshake failed: {}", error),
+            HandshakeError::Refused(reason) => write!(f, "Handshake refused: {}", reason),
+            HandshakeError::NetworkMagicMismatch { expected, received } => write!(
+                f,
+                "Network magic mismatch: peer is on {}, expected {}",
+                received, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<HandshakeError> for Error {
+    fn from(error: HandshakeError) -> Error {
+        error.to_string()
+    }
+}
+
+impl MessageOps for Message {
+    fn from_iter(mut array: Values) -> Result<Self, Error> {
+        match array.integer()? {
+            // The server refuses undecodable versions once chosen.
+            0 => Ok(Message::ProposeVersions(versions(array.map()?)?)),
             1 => {
-                let version = Version::from_u16(array.integer()? as u16);
-                let mut items = array.array()?;
-                let magic = items.integer()? as u32;
-                let _false = items.bool()?;
-                // TODO: Handle this value.
-                assert_eq!(_false, false);
-                Ok(Message::AcceptVersion(version, magic))
+                let mut values = array.into_vec().into_iter();
+                match (values.next(), values.next(), values.next()) {
+                    (Some(key), Some(value), None) => {
+                        Ok(Message::AcceptVersion(Version::from_values(key, value)?))
+                    }
+                    _ => Err("Version and version data required.".to_string()),
+                }
             }
             2 => {
-                let reason = array.array()?;
-                error!("Handshake refused with reason: {:?}", reason);
-                Ok(Message::Refuse)
+                let reason = RefuseReason::from_iter(array.array()?)?;
+                error!("Handshake refused with reason: {}", reason);
+                Ok(Message::Refuse(reason))
             }
-            _ => return Err("Unexpected.".to_string()),
+            3 => Ok(Message::QueryReply(versions(array.map()?)?)),
+            _ => Err("Unexpected.".to_string()),
         }
     }
 
@@ -79,39 +190,81 @@ impl MessageOps for Message {
         match self {
             Message::ProposeVersions(versions) => vec![
                 Integer(0),
-                Value::Map(
-                    versions
-                        .iter()
-                        .map(|(v, m)| v.to_values(*m).unwrap())
-                        .collect(),
-                ),
+                Value::Map(versions.iter().map(Version::to_values).collect()),
             ],
-            Message::AcceptVersion(version, magic) => vec![
-                Value::Integer(1),
-                Value::Integer(version.to_u16().into()),
-                Array(vec![Value::Integer((*magic).into()), Bool(false)]),
-            ],
-            Message::Refuse => vec![
-                Value::Integer(1),
-                // TODO: Get more information about RefuseReason format.
-                Array(vec![
-                    Value::Integer(2),
-                    Value::Integer(0),
-                    Value::Text("Refused.".to_string()),
-                ]),
+            Message::AcceptVersion(version) => {
+                let (key, value) = version.to_values();
+                vec![Value::Integer(1), key, value]
+            }
+            Message::Refuse(reason) => vec![Value::Integer(2), Array(reason.to_values())],
+            Message::QueryReply(versions) => vec![
+                Integer(3),
+                Value::Map(versions.iter().map(Version::to_values).collect()),
             ],
         }
     }
 }
 
 pub struct HandshakeBuilder {
-    versions: Vec<Version>,
+    versions: Vec<u16>,
+    node_to_client: bool,
     magic: u32,
+    initiator_only: bool,
+    peer_sharing: PeerSharing,
+    query: bool,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeToNodeVersionData {
+    pub network_magic: u32,
+    // Versions 4 and later.
+    pub initiator_only_diffusion_mode: bool,
+    // Versions 11 and later.
+    pub peer_sharing: PeerSharing,
+    pub query: bool,
+}
+
+// Ordered by how much is shared, both sides must agree to share.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum PeerSharing {
+    Disabled,
+    // Versions 11 and 12 only: peers are shared, our address is not.
+    Private,
+    Enabled,
+}
+
+impl PeerSharing {
+    fn from_value(version: u16, value: i128) -> Result<Self, Error> {
+        match (version, value) {
+            (_, 0) => Ok(PeerSharing::Disabled),
+            (11..=12, 1) => Ok(PeerSharing::Private),
+            (11..=12, 2) | (13.., 1) => Ok(PeerSharing::Enabled),
+            _ => Err(format!("Invalid peer sharing: {}", value)),
+        }
+    }
+
+    fn to_value(self, version: u16) -> Value {
+        Value::Integer(match (version, self) {
+            (_, PeerSharing::Disabled) => 0,
+            (11..=12, PeerSharing::Private) => 1,
+            (11..=12, PeerSharing::Enabled) => 2,
+            // Private mode is gone from version 13, don't share our address.
+            (_, PeerSharing::Private) => 0,
+            (_, PeerSharing::Enabled) => 1,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeToClientVersionData {
+    pub network_magic: u32,
+    // Versions 15 and later.
+    pub query: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Version {
-    N2N(i128),
+    N2N(u16, NodeToNodeVersionData),
     // 1: initial version
     // 2: added local-query mini-protocol
     // 3:
@@ -119,7 +272,14 @@ pub enum Version {
     // 5: Allegra
     // 6: Mary
     // 7: Alonzo
-    C2N(i128),
+    // 8: block fetch pipelining
+    // 9: Babbage
+    // 10: full duplex connections
+    // 11: peer sharing and query
+    // 12: no observable changes
+    // 13: peer sharing without private mode
+    // 14: Conway
+    C2N(u16, NodeToClientVersionData),
     // 1: initial version
     // 2: added local-query mini-protocol
     // 3:
@@ -129,87 +289,205 @@ pub enum Version {
     // 7: new queries added to local state query mini-protocol
     // 8: codec changed for local state query mini-protocol
     // 9: Updates for Alonzo
+    // 10: chain block number and chain point queries
+    // 11: reward info queries
+    // 12: local tx monitor
+    // 13: Babbage blocks in CBOR-in-CBOR
+    // 14: pool distribution and stake snapshot queries
+    // 15: query flag, Conway
+    // 16: Conway transaction encoding
+    // A version number we don't know, with its version data undecoded.
+    Unknown(u16, Value),
 }
 
 impl Version {
-    fn to_values(&self, magic: u32) -> Result<(Value, Value), Error> {
+    // The version number as sent, C2N versions have bit 15 set.
+    pub fn number(&self) -> u16 {
         match self {
-            &Version::N2N(v) => match v {
-                1..=3 => Ok((Value::Integer(v), Value::Integer(magic.into()))),
-                4..=7 => Ok((
-                    Value::Integer(v),
-                    Value::Array(vec![Value::Integer(magic as i128), Value::Bool(false)]),
-                )),
-                _ => Err("Unsupported version.".to_string()),
-            },
-            &Version::C2N(v) => match v {
-                1..=9 => Ok((Value::Integer(0x8000 ^ v), Value::Integer(magic.into()))),
-                _ => Err("Unsupported version.".to_string()),
-            },
+            Version::N2N(version, _) => *version,
+            Version::C2N(version, _) => 0x8000 ^ version,
+            Version::Unknown(version, _) => *version,
         }
     }
 
-    fn from_values(key: Value, value: Value) -> Result<(Version, u32), Error> {
-        let version = match key {
-            Value::Integer(version) => version as u32,
-            _ => return Err("Version required.".to_string()),
-        };
+    pub fn network_magic(&self) -> Option<u32> {
+        match self {
+            Version::N2N(_, data) => Some(data.network_magic),
+            Version::C2N(_, data) => Some(data.network_magic),
+            Version::Unknown(..) => None,
+        }
+    }
+
+    pub fn query(&self) -> bool {
+        match self {
+            Version::N2N(_, data) => data.query,
+            Version::C2N(_, data) => data.query,
+            Version::Unknown(..) => false,
+        }
+    }
+
+    // The version agreed on when accepting `proposed`, `self` being ours.
+    fn accept(&self, proposed: &Version) -> Version {
+        match (self, proposed) {
+            (Version::N2N(version, ours), Version::N2N(_, theirs)) => Version::N2N(
+                *version,
+                NodeToNodeVersionData {
+                    network_magic: ours.network_magic,
+                    initiator_only_diffusion_mode: ours.initiator_only_diffusion_mode
+                        || theirs.initiator_only_diffusion_mode,
+                    peer_sharing: ours.peer_sharing.min(theirs.peer_sharing),
+                    query: ours.query || theirs.query,
+                },
+            ),
+            (Version::C2N(version, ours), Version::C2N(_, theirs)) => Version::C2N(
+                *version,
+                NodeToClientVersionData {
+                    network_magic: ours.network_magic,
+                    query: ours.query || theirs.query,
+                },
+            ),
+            _ => self.clone(),
+        }
+    }
+
+    fn to_values(&self) -> (Value, Value) {
+        match self {
+            Version::N2N(version, data) => {
+                let magic = Value::Integer(data.network_magic.into());
+                let diffusion_mode = Value::Bool(data.initiator_only_diffusion_mode);
+                let value = match version {
+                    0..=3 => magic,
+                    4..=10 => Value::Array(vec![magic, diffusion_mode]),
+                    // Later versions are assumed to keep the latest layout.
+                    _ => Value::Array(vec![
+                        magic,
+                        diffusion_mode,
+                        data.peer_sharing.to_value(*version),
+                        Value::Bool(data.query),
+                    ]),
+                };
+                (Value::Integer(self.number().into()), value)
+            }
+            Version::C2N(version, data) => {
+                let magic = Value::Integer(data.network_magic.into());
+                let value = match version {
+                    0..=14 => magic,
+                    _ => Value::Array(vec![magic, Value::Bool(data.query)]),
+                };
+                (Value::Integer(self.number().into()), value)
+            }
+            Version::Unknown(version, data) => (Value::Integer((*version).into()), data.clone()),
+        }
+    }
+
+    fn from_values(key: Value, value: Value) -> Result<Version, Error> {
+        Version::decode(version_key(&key)?, value)
+    }
+
+    fn decode(version: u16, value: Value) -> Result<Version, Error> {
         match version {
             0x0001..=0x0003 => {
                 let magic = match value {
-                    Value::Integer(magic) => magic as u32,
+                    Value::Integer(magic) => network_magic(magic)?,
                     _ => return Err("Magic required.".to_string()),
                 };
-                Ok((Version::N2N(version.into()), magic))
+                let data = NodeToNodeVersionData {
+                    network_magic: magic,
+                    initiator_only_diffusion_mode: false,
+                    peer_sharing: PeerSharing::Disabled,
+                    query: false,
+                };
+                Ok(Version::N2N(version, data))
             }
-            0x0004..=0x0007 => {
-                let mut values = match value {
+            0x0004..=0x000e => {
+                let params = match value {
                     Value::Array(params) => params,
                     _ => return Err("Parameters required.".to_string()),
-                }
-                .into_iter();
-                let magic = match values.next() {
-                    Some(Value::Integer(magic)) => magic as u32,
-                    _ => return Err("Magic required.".to_string()),
                 };
-                match values.next() {
-                    Some(Value::Bool(false)) => (),
-                    _ => return Err("False expected.".to_string()),
-                }
-                Ok((Version::N2N(version.into()), magic))
+                let mut values = Values::from_vec(&params);
+                let network_magic = network_magic(values.integer()?)?;
+                let initiator_only_diffusion_mode = values.bool()?;
+                let (peer_sharing, query) = match version {
+                    0x000b..=0x000e => (
+                        PeerSharing::from_value(version, values.integer()?)?,
+                        values.bool()?,
+                    ),
+                    _ => (PeerSharing::Disabled, false),
+                };
+                values.end()?;
+                let data = NodeToNodeVersionData {
+                    network_magic,
+                    initiator_only_diffusion_mode,
+                    peer_sharing,
+                    query,
+                };
+                Ok(Version::N2N(version, data))
             }
-            0x8001..=0x8009 => {
+            0x8001..=0x800e => {
                 let magic = match value {
-                    Value::Integer(magic) => magic as u32,
+                    Value::Integer(magic) => network_magic(magic)?,
                     _ => return Err("Magic required.".to_string()),
                 };
-                Ok((Version::C2N((0x8000 ^ version).into()), magic))
+                let data = NodeToClientVersionData {
+                    network_magic: magic,
+                    query: false,
+                };
+                Ok(Version::C2N(0x8000 ^ version, data))
             }
-            _ => return Err(format!("Unsupported version number: {}", version)),
+            0x800f..=0x8010 => {
+                let params = match value {
+                    Value::Array(params) => params,
+                    _ => return Err("Parameters required.".to_string()),
+                };
+                let mut values = Values::from_vec(&params);
+                let data = NodeToClientVersionData {
+                    network_magic: network_magic(values.integer()?)?,
+                    query: values.bool()?,
+                };
+                values.end()?;
+                Ok(Version::C2N(0x8000 ^ version, data))
+            }
+            _ => Ok(Version::Unknown(version, value)),
         }
     }
 }
 
-impl Version {
-    fn from_u16(value: u16) -> Self {
-        match value & 0x8000 != 0 {
-            false => Version::N2N(value.into()),
-            true => Version::C2N((value ^ 0x8000).into()),
-        }
+// Unknown versions and data that doesn't decode are kept as received.
+fn versions(map: &BTreeMap<Value, Value>) -> Result<Vec<Version>, Error> {
+    let mut versions = Vec::new();
+    for (key, value) in map {
+        let version = version_key(key)?;
+        versions.push(
+            Version::decode(version, value.clone())
+                .unwrap_or_else(|_| Version::Unknown(version, value.clone())),
+        );
     }
+    Ok(versions)
+}
 
-    fn to_u16(&self) -> u16 {
-        match self {
-            Version::N2N(version) => u16::try_from(*version).unwrap(),
-            Version::C2N(version) => 0x8000 ^ u16::try_from(*version).unwrap(),
-        }
+fn version_number(value: i128) -> Result<u16, Error> {
+    u16::try_from(value).map_err(|_| format!("Invalid version number: {}", value))
+}
+
+fn version_key(key: &Value) -> Result<u16, Error> {
+    match key {
+        Value::Integer(version) => version_number(*version),
+        _ => Err("Version required.".to_string()),
     }
 }
 
+fn network_magic(value: i128) -> Result<u32, Error> {
+    u32::try_from(value).map_err(|_| format!("Invalid network magic: {}", value))
+}
+
 pub fn builder() -> HandshakeBuilder {
     HandshakeBuilder {
-        versions: vec![Version::N2N(6), Version::N2N(7)],
+        versions: (7..=14).collect(),
+        node_to_client: false,
         magic: 0,
+        initiator_only: false,
+        peer_sharing: PeerSharing::Disabled,
+        query: false,
     }
 }
 
@@ -219,16 +497,56 @@ impl HandshakeBuilder {
         self
     }
 
+    pub fn initiator_only(&mut self, initiator_only: bool) -> &mut Self {
+        self.initiator_only = initiator_only;
+        self
+    }
+
+    pub fn peer_sharing(&mut self, peer_sharing: PeerSharing) -> &mut Self {
+        self.peer_sharing = peer_sharing;
+        self
+    }
+
+    // Ask for the peer's versions instead of opening a session. Only N2N 11
+    // and later and C2N 15 and later carry the flag.
+    pub fn query(&mut self) -> &mut Self {
+        self.query = true;
+        self
+    }
+
     pub fn node_to_node(&mut self) -> &mut Self {
-        self.versions = vec![Version::N2N(6), Version::N2N(7)];
+        self.versions = (7..=14).collect();
+        self.node_to_client = false;
         self
     }
 
     pub fn client_to_node(&mut self) -> &mut Self {
-        self.versions = vec![Version::C2N(9)];
+        self.versions = (1..=16).collect();
+        self.node_to_client = true;
         self
     }
 
+    fn version(&self, version: u16) -> Version {
+        match self.node_to_client {
+            false => Version::N2N(
+                version,
+                NodeToNodeVersionData {
+                    network_magic: self.magic,
+                    initiator_only_diffusion_mode: self.initiator_only,
+                    peer_sharing: self.peer_sharing,
+                    query: self.query,
+                },
+            ),
+            true => Version::C2N(
+                version,
+                NodeToClientVersionData {
+                    network_magic: self.magic,
+                    query: self.query,
+                },
+            ),
+        }
+    }
+
     fn build<'a>(
         &self,
         connection: &'a mut Connection,
@@ -241,10 +559,16 @@ impl HandshakeBuilder {
                 _ => panic!(),
             }),
             role,
-            versions: self.versions.clone(),
-            network_magic: self.magic,
+            versions: self
+                .versions
+                .iter()
+                .map(|version| self.version(*version))
+                .collect(),
             state: State::Propose,
-            version: None,
+            outcome: None,
+            proposed: Vec::new(),
+            refusal: None,
+            mismatch: None,
         })
     }
 
@@ -257,22 +581,99 @@ impl HandshakeBuilder {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Accepted(Version),
+    // The peer's versions, when either side queried.
+    Queried(Vec<Version>),
+}
+
 pub struct Handshake<'a> {
     channel: Channel<'a>,
     role: Agency,
     versions: Vec<Version>,
-    network_magic: u32,
     state: State,
-    version: Option<Version>,
+    outcome: Option<Outcome>,
+    // Versions proposed by the client, used by the server.
+    proposed: Vec<Version>,
+    refusal: Option<RefuseReason>,
+    // Our and the peer's network magic, when they differ.
+    mismatch: Option<(u32, u32)>,
 }
 
 impl Handshake<'_> {
-    pub async fn negotiate(&mut self) -> Result<(Version, u32), Error> {
-        self.execute().await?;
-        self.version
-            .as_ref()
-            .map(|v| (v.clone(), self.network_magic))
-            .ok_or("Handshake failed.".to_string())
+    // The agreed version and version data, once negotiated.
+    pub fn version(&self) -> Option<&Version> {
+        match &self.outcome {
+            Some(Outcome::Accepted(version)) => Some(version),
+            _ => None,
+        }
+    }
+
+    pub async fn negotiate(&mut self) -> Result<Outcome, HandshakeError> {
+        self.execute().await.map_err(HandshakeError::Failed)?;
+        if let Some((expected, received)) = self.mismatch {
+            return Err(HandshakeError::NetworkMagicMismatch { expected, received });
+        }
+        if let Some(reason) = &self.refusal {
+            return Err(HandshakeError::Refused(reason.clone()));
+        }
+        self.outcome
+            .clone()
+            .ok_or_else(|| HandshakeError::Failed("No version accepted.".to_string()))
+    }
+
+    fn refuse(&mut self, reason: RefuseReason) -> Message {
+        self.refusal = Some(reason.clone());
+        Message::Refuse(reason)
+    }
+
+    // Accept the highest version known to both sides, if its data decodes
+    // and the network magic matches.
+    fn confirm(&mut self) -> Message {
+        let common = self
+            .proposed
+            .iter()
+            .filter_map(|theirs| {
+                let ours = self
+                    .versions
+                    .iter()
+                    .find(|ours| ours.number() == theirs.number())?;
+                Some((ours.clone(), theirs.clone()))
+            })
+            .max_by_key(|(ours, _)| ours.number());
+        let (ours, theirs) = match common {
+            Some(common) => common,
+            None => {
+                let versions = self.versions.iter().map(Version::number).collect();
+                return self.refuse(RefuseReason::VersionMismatch(versions));
+            }
+        };
+        if let Version::Unknown(version, data) = &theirs {
+            if let Err(message) = Version::decode(*version, data.clone()) {
+                return self.refuse(RefuseReason::HandshakeDecodeError(*version, message));
+            }
+        }
+        // Queries are answered whatever the network magic.
+        let version = ours.accept(&theirs);
+        if version.query() {
+            self.outcome = Some(Outcome::Queried(self.proposed.clone()));
+            return Message::QueryReply(self.versions.clone());
+        }
+        match (ours.network_magic(), theirs.network_magic()) {
+            (Some(expected), Some(received)) if expected != received => {
+                self.mismatch = Some((expected, received));
+                let message = format!(
+                    "Network magic mismatch: {} proposed, {} expected.",
+                    received, expected
+                );
+                self.refuse(RefuseReason::Refused(ours.number(), message))
+            }
+            _ => {
+                self.outcome = Some(Outcome::Accepted(version.clone()));
+                Message::AcceptVersion(version)
+            }
+        }
     }
 }
 
@@ -294,11 +695,11 @@ impl<'a> Protocol<'a> for Handshake<'a> {
     }
 
     fn agency(&self) -> Agency {
-        return match self.state {
+        match self.state {
             State::Propose => Agency::Client,
             State::Confirm => Agency::Server,
             State::Done => Agency::None,
-        };
+        }
     }
 
     fn state(&self) -> Self::State {
@@ -310,54 +711,57 @@ impl<'a> Protocol<'a> for Handshake<'a> {
         match self.state {
             State::Propose => {
                 self.state = State::Confirm;
-                Ok(Message::ProposeVersions(
-                    self.versions
-                        .iter()
-                        .map(|v| (v.clone(), self.network_magic))
-                        .collect(),
-                ))
+                Ok(Message::ProposeVersions(self.versions.clone()))
             }
             State::Confirm => {
                 self.state = State::Done;
-                let version = self
-                    .versions
-                    .last()
-                    .ok_or("No versions available.".to_string())?;
-                self.version = Some(version.clone());
-                Ok(Message::AcceptVersion(version.clone(), self.network_magic))
+                Ok(self.confirm())
             }
-            State::Done => panic!("unexpected send"),
+            other => Err(format!("Unexpected state: {:?}", other)),
         }
     }
 
     fn recv(&mut self, message: Message) -> Result<(), Error> {
         debug!("recv: {:?}", self.state);
         match self.state {
-            State::Propose => {
-                self.state = State::Confirm;
-            }
+            State::Propose => match message {
+                Message::ProposeVersions(versions) => {
+                    self.state = State::Confirm;
+                    self.proposed = versions;
+                }
+                _ => return Err("Unexpected message.".to_string()),
+            },
             State::Confirm => match message {
-                Message::AcceptVersion(version, magic) => {
+                Message::AcceptVersion(version) => {
                     self.state = State::Done;
-                    self.version = self
+                    let ours = self
                         .versions
                         .iter()
-                        .filter(|v| **v == version)
-                        .next()
-                        .cloned();
-                    assert_eq!(magic, self.network_magic);
+                        .find(|ours| ours.number() == version.number())
+                        .ok_or_else(|| "Unexpected version accepted.".to_string())?;
+                    match (ours.network_magic(), version.network_magic()) {
+                        (Some(expected), Some(received)) if expected != received => {
+                            self.mismatch = Some((expected, received))
+                        }
+                        _ => self.outcome = Some(Outcome::Accepted(version)),
+                    }
                 }
-                Message::Refuse => {
+                Message::Refuse(reason) => {
                     self.state = State::Done;
+                    self.refusal = Some(reason);
+                }
+                Message::QueryReply(versions) => {
+                    self.state = State::Done;
+                    self.outcome = Some(Outcome::Queried(versions));
                 }
                 _ => return Err("Unexpected message.".to_string()),
             },
-            State::Done => panic!("unexpected recv"),
+            other => return Err(format!("Unexpected state: {:?}", other)),
         }
         Ok(())
     }
 
-    fn channel<'b>(&'b mut self) -> &mut Channel<'a>
+    fn channel<'b>(&'b mut self) -> &'b mut Channel<'a>
     where
         'a: 'b,
     {
@@ -373,34 +777,88 @@ mod tests {
 
     use super::*;
 
+    fn params(magic: u32, version: i128) -> Value {
+        match version {
+            4..=10 => Array(vec![Integer(magic.into()), Bool(false)]),
+            _ => Array(vec![
+                Integer(magic.into()),
+                Bool(false),
+                Integer(0),
+                Bool(false),
+            ]),
+        }
+    }
+
     fn propose(magic: u32) -> Vec<u8> {
         to_vec(&Array(vec![
             Integer(0),
-            Map(vec![
-                (Integer(6), Array(vec![Integer(magic.into()), Bool(false)])),
-                (Integer(7), Array(vec![Integer(magic.into()), Bool(false)])),
-            ]
-            .into_iter()
-            .collect::<BTreeMap<Value, Value>>()),
+            Map((7..=14)
+                .map(|version| (Integer(version), params(magic, version)))
+                .collect::<BTreeMap<Value, Value>>()),
         ]))
         .unwrap()
     }
 
     fn confirm(magic: u32) -> Vec<u8> {
-        to_vec(&Array(vec![
-            Integer(1),
-            Integer(7),
-            Array(vec![Integer(magic.into()), Bool(false)]),
-        ]))
-        .unwrap()
+        to_vec(&Array(vec![Integer(1), Integer(14), params(magic, 14)])).unwrap()
+    }
+
+    fn data(magic: u32) -> NodeToNodeVersionData {
+        NodeToNodeVersionData {
+            network_magic: magic,
+            initiator_only_diffusion_mode: false,
+            peer_sharing: PeerSharing::Disabled,
+            query: false,
+        }
+    }
+
+    fn n2c(query: bool) -> NodeToClientVersionData {
+        NodeToClientVersionData {
+            network_magic: 0x12345678,
+            query,
+        }
     }
 
     #[test]
     fn message_cbor_works() {
         let messages = [
-            Message::ProposeVersions((1..7).map(|n| (Version::N2N(n), 0x12345678)).collect()),
-            Message::ProposeVersions((1..9).map(|n| (Version::C2N(n), 0x12345678)).collect()),
-            Message::AcceptVersion(Version::N2N(7), 0x87564321),
+            Message::ProposeVersions(
+                (1..=14)
+                    .map(|n| Version::N2N(n, data(0x12345678)))
+                    .collect(),
+            ),
+            Message::ProposeVersions((1..=14).map(|n| Version::C2N(n, n2c(false))).collect()),
+            Message::ProposeVersions((15..=16).map(|n| Version::C2N(n, n2c(true))).collect()),
+            Message::AcceptVersion(Version::C2N(16, n2c(true))),
+            Message::AcceptVersion(Version::N2N(7, data(0x87564321))),
+            Message::AcceptVersion(Version::N2N(
+                14,
+                NodeToNodeVersionData {
+                    network_magic: 0x87564321,
+                    initiator_only_diffusion_mode: true,
+                    peer_sharing: PeerSharing::Enabled,
+                    query: true,
+                },
+            )),
+            Message::Refuse(RefuseReason::VersionMismatch(vec![6, 7])),
+            Message::QueryReply(
+                (1..=14)
+                    .map(|n| Version::N2N(n, data(0x12345678)))
+                    .collect(),
+            ),
+            // Versions we don't know or can't decode are kept as received.
+            Message::QueryReply(vec![
+                Version::N2N(7, data(0x12345678)),
+                Version::Unknown(8, Text("invalid".to_string())),
+                Version::N2N(14, data(0x12345678)),
+                Version::Unknown(15, Array(vec![Integer(0x12345678), Bool(false)])),
+                Version::Unknown(0x8011, Text("future".to_string())),
+            ]),
+            Message::Refuse(RefuseReason::HandshakeDecodeError(
+                7,
+                "Invalid.".to_string(),
+            )),
+            Message::Refuse(RefuseReason::Refused(7, "Refused.".to_string())),
         ];
         for message in messages {
             assert_eq!(
@@ -413,7 +871,7 @@ mod tests {
     #[tokio::test]
     async fn handshake_client_works() {
         env_logger::builder().is_test(true).try_init().ok();
-        let (mut connection, mut endpoint) = Connection::test_unix_pair().unwrap();
+        let (mut connection, endpoint) = Connection::test_unix_pair().unwrap();
         let mut channel = endpoint.channel(0x8000);
 
         let magic = 0xdddddddd;
@@ -428,7 +886,10 @@ mod tests {
                 assert_eq!(client.state, State::Propose);
                 let result = client.negotiate().await.unwrap();
                 assert_eq!(client.state, State::Done);
-                assert_eq!(result, (Version::N2N(7), 0xdddddddd));
+                assert_eq!(
+                    result,
+                    Outcome::Accepted(Version::N2N(14, data(0xdddddddd)))
+                );
             },
             async {
                 let request = channel.recv().await.unwrap();
@@ -441,7 +902,7 @@ mod tests {
     #[tokio::test]
     async fn handshake_server_works() {
         env_logger::builder().is_test(true).try_init().ok();
-        let (mut connection, mut endpoint) = Connection::test_unix_pair().unwrap();
+        let (mut connection, endpoint) = Connection::test_unix_pair().unwrap();
         let mut channel = endpoint.channel(0x0000);
 
         let magic = 0xdddddddd;
@@ -455,7 +916,7 @@ mod tests {
                 assert_eq!(server.state, State::Propose);
                 let result = server.negotiate().await.unwrap();
                 assert_eq!(server.state, State::Done);
-                assert_eq!(result, (Version::N2N(7), magic));
+                assert_eq!(result, Outcome::Accepted(Version::N2N(14, data(magic))));
             },
             async {
                 channel.send(&propose(magic)).await.unwrap();
@@ -464,4 +925,400 @@ mod tests {
             },
         );
     }
+
+    #[tokio::test]
+    async fn handshake_server_negotiates() {
+        let (mut connection, endpoint) = Connection::test_unix_pair().unwrap();
+        let mut channel = endpoint.channel(0x0000);
+
+        let magic = 0xdddddddd;
+        let mismatch = RefuseReason::VersionMismatch((7..=14).collect());
+        let refused = RefuseReason::Refused(
+            7,
+            "Network magic mismatch: 42 proposed, 3722304989 expected.".to_string(),
+        );
+        let proposed = NodeToNodeVersionData {
+            network_magic: magic,
+            initiator_only_diffusion_mode: true,
+            peer_sharing: PeerSharing::Enabled,
+            query: false,
+        };
+        let accepted = NodeToNodeVersionData {
+            network_magic: magic,
+            initiator_only_diffusion_mode: true,
+            peer_sharing: PeerSharing::Disabled,
+            query: false,
+        };
+        let cases = [
+            // Highest common version is accepted.
+            (
+                vec![(8, data(magic)), (9, data(magic)), (10, data(magic))],
+                Ok(Outcome::Accepted(Version::N2N(10, data(magic)))),
+                Message::AcceptVersion(Version::N2N(10, data(magic))),
+            ),
+            (
+                vec![(4, data(magic)), (5, data(magic))],
+                Err(HandshakeError::Refused(mismatch.clone())),
+                Message::Refuse(mismatch),
+            ),
+            (
+                vec![(7, data(42))],
+                Err(HandshakeError::NetworkMagicMismatch {
+                    expected: magic,
+                    received: 42,
+                }),
+                Message::Refuse(refused),
+            ),
+            // Peer sharing needs both sides, diffusion mode either side.
+            (
+                vec![(14, proposed)],
+                Ok(Outcome::Accepted(Version::N2N(14, accepted.clone()))),
+                Message::AcceptVersion(Version::N2N(14, accepted)),
+            ),
+        ];
+        for (versions, result, response) in cases {
+            tokio::join!(
+                async {
+                    let mut server = builder()
+                        .node_to_node()
+                        .network_magic(magic)
+                        .server(&mut connection)
+                        .unwrap();
+                    assert_eq!(server.negotiate().await, result);
+                },
+                async {
+                    let versions = versions
+                        .into_iter()
+                        .map(|(version, magic)| Version::N2N(version, magic))
+                        .collect();
+                    let request = Message::ProposeVersions(versions);
+                    channel.send(&request.to_bytes()).await.unwrap();
+                    channel.expect(&response.to_bytes()).await;
+                },
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_server_skips_unknown_versions() {
+        let (mut connection, endpoint) = Connection::test_unix_pair().unwrap();
+        let mut channel = endpoint.channel(0x0000);
+
+        let magic = 0xdddddddd;
+        let accept = Message::AcceptVersion(Version::N2N(14, data(magic)));
+        let mismatch = Message::Refuse(RefuseReason::VersionMismatch((7..=14).collect()));
+        for (versions, response) in [(vec![14, 15, 16], accept), (vec![15, 16], mismatch)] {
+            tokio::join!(
+                async {
+                    let mut server = builder()
+                        .node_to_node()
+                        .network_magic(magic)
+                        .server(&mut connection)
+                        .unwrap();
+                    server.negotiate().await.ok();
+                },
+                async {
+                    // Future versions may use any data.
+                    let request = Array(vec![
+                        Integer(0),
+                        Map(versions
+                            .into_iter()
+                            .map(|version| match version {
+                                14 => (Integer(version), params(magic, version)),
+                                _ => (Integer(version), Text("future".to_string())),
+                            })
+                            .collect()),
+                    ]);
+                    channel.send(&to_vec(&request).unwrap()).await.unwrap();
+                    channel.expect(&response.to_bytes()).await;
+                },
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_server_refuses_undecodable_data() {
+        let (mut connection, endpoint) = Connection::test_unix_pair().unwrap();
+        let mut channel = endpoint.channel(0x0000);
+
+        let magic = 0xdddddddd;
+        let reason = RefuseReason::HandshakeDecodeError(14, "Parameters required.".to_string());
+        tokio::join!(
+            async {
+                let mut server = builder()
+                    .node_to_node()
+                    .network_magic(magic)
+                    .server(&mut connection)
+                    .unwrap();
+                assert_eq!(
+                    server.negotiate().await,
+                    Err(HandshakeError::Refused(reason.clone()))
+                );
+            },
+            async {
+                // Only the chosen version's data is decoded.
+                let request = Array(vec![
+                    Integer(0),
+                    Map([
+                        (Integer(13), params(magic, 13)),
+                        (Integer(14), Text("invalid".to_string())),
+                    ]
+                    .into_iter()
+                    .collect()),
+                ]);
+                channel.send(&to_vec(&request).unwrap()).await.unwrap();
+                channel
+                    .expect(&Message::Refuse(reason.clone()).to_bytes())
+                    .await;
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_query_reveals_network() {
+        let (mut connection, mut endpoint) = Connection::test_unix_pair().unwrap();
+
+        // Refused like by a node on another network, which still answers
+        // queries.
+        for query in [false, true] {
+            tokio::join!(
+                async {
+                    let mut builder = builder();
+                    builder.node_to_node().network_magic(764824073);
+                    if query {
+                        builder.query();
+                    }
+                    let mut client = builder.client(&mut connection).unwrap();
+                    match (query, client.negotiate().await) {
+                        (false, Err(HandshakeError::Refused(RefuseReason::Refused(14, _)))) => (),
+                        (true, Ok(Outcome::Queried(versions))) => {
+                            assert!(versions.iter().all(|v| v.network_magic() == Some(1)));
+                        }
+                        (_, other) => panic!("Unexpected result: {:?}", other),
+                    }
+                },
+                async {
+                    let mut server = builder()
+                        .node_to_node()
+                        .network_magic(1)
+                        .server(&mut endpoint)
+                        .unwrap();
+                    server.negotiate().await.ok();
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn version_data_cbor_works() {
+        let data = NodeToNodeVersionData {
+            network_magic: 1,
+            initiator_only_diffusion_mode: true,
+            peer_sharing: PeerSharing::Enabled,
+            query: false,
+        };
+        let message = Message::AcceptVersion(Version::N2N(14, data.clone()));
+        assert_eq!(hex::encode(message.to_bytes()), "83010e8401f501f4");
+        let message = Message::AcceptVersion(Version::N2N(10, data));
+        assert_eq!(hex::encode(message.to_bytes()), "83010a8201f5");
+        let data = NodeToClientVersionData {
+            network_magic: 1,
+            query: true,
+        };
+        let message = Message::AcceptVersion(Version::C2N(16, data.clone()));
+        assert_eq!(hex::encode(message.to_bytes()), "83011980108201f5");
+        let message = Message::AcceptVersion(Version::C2N(14, data));
+        assert_eq!(hex::encode(message.to_bytes()), "830119800e01");
+    }
+
+    #[test]
+    fn peer_sharing_cbor_works() {
+        let version = |version, peer_sharing| {
+            let data = NodeToNodeVersionData {
+                peer_sharing,
+                ..data(1)
+            };
+            Version::N2N(version, data)
+        };
+        let cases = [
+            (version(12, PeerSharing::Private), "83010c8401f401f4"),
+            (version(12, PeerSharing::Enabled), "83010c8401f402f4"),
+            (version(13, PeerSharing::Enabled), "83010d8401f401f4"),
+        ];
+        for (version, encoded) in cases {
+            let message = Message::AcceptVersion(version);
+            assert_eq!(hex::encode(message.to_bytes()), encoded);
+            assert_eq!(
+                Message::from_iter(Values::from_vec(&message.to_values())),
+                Ok(message)
+            );
+        }
+        // Private mode is gone from version 13.
+        let message = Message::AcceptVersion(version(13, PeerSharing::Private));
+        assert_eq!(hex::encode(message.to_bytes()), "83010d8401f400f4");
+        let invalid = [
+            (
+                13,
+                Array(vec![Integer(1), Bool(false), Integer(2), Bool(false)]),
+            ),
+            (
+                14,
+                Array(vec![Integer(1 << 32), Bool(false), Integer(0), Bool(false)]),
+            ),
+            (1, Integer(-1)),
+        ];
+        for (version, params) in invalid {
+            let message = [Integer(1), Integer(version), params];
+            assert!(Message::from_iter(Values::from_vec(&message)).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_client_to_node_works() {
+        let (mut connection, mut endpoint) = Connection::test_unix_pair().unwrap();
+
+        let agreed = Version::C2N(16, n2c(false));
+        tokio::join!(
+            async {
+                let mut client = builder()
+                    .client_to_node()
+                    .network_magic(0x12345678)
+                    .client(&mut connection)
+                    .unwrap();
+                assert_eq!(
+                    client.negotiate().await,
+                    Ok(Outcome::Accepted(agreed.clone()))
+                );
+                assert_eq!(client.version(), Some(&agreed));
+            },
+            async {
+                let mut server = builder()
+                    .client_to_node()
+                    .network_magic(0x12345678)
+                    .server(&mut endpoint)
+                    .unwrap();
+                assert_eq!(
+                    server.negotiate().await,
+                    Ok(Outcome::Accepted(agreed.clone()))
+                );
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_query_works() {
+        let (mut connection, mut endpoint) = Connection::test_unix_pair().unwrap();
+
+        let magic = 0xdddddddd;
+        let table = |query| -> Vec<Version> {
+            (7..=14)
+                .map(|n| {
+                    let data = NodeToNodeVersionData {
+                        network_magic: magic,
+                        initiator_only_diffusion_mode: false,
+                        peer_sharing: PeerSharing::Disabled,
+                        // Older versions can't carry the flag.
+                        query: query && n >= 11,
+                    };
+                    Version::N2N(n, data)
+                })
+                .collect()
+        };
+        // Either side may ask for the query.
+        for client_query in [true, false] {
+            tokio::join!(
+                async {
+                    let mut builder = builder();
+                    builder.node_to_node().network_magic(magic);
+                    if client_query {
+                        builder.query();
+                    }
+                    let mut client = builder.client(&mut connection).unwrap();
+                    assert_eq!(
+                        client.negotiate().await,
+                        Ok(Outcome::Queried(table(!client_query)))
+                    );
+                    assert_eq!(client.version(), None);
+                },
+                async {
+                    let mut builder = builder();
+                    builder.node_to_node().network_magic(magic);
+                    if !client_query {
+                        builder.query();
+                    }
+                    let mut server = builder.server(&mut endpoint).unwrap();
+                    assert_eq!(
+                        server.negotiate().await,
+                        Ok(Outcome::Queried(table(client_query)))
+                    );
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn refuse_reason_cbor_works() {
+        let message = Message::Refuse(RefuseReason::VersionMismatch(vec![6, 7]));
+        assert_eq!(hex::encode(message.to_bytes()), "82028200820607");
+        let message = Message::Refuse(RefuseReason::Refused(7, "x".to_string()));
+        assert_eq!(hex::encode(message.to_bytes()), "82028302076178");
+        // Every entry must be a version number.
+        for version in [Text("7".to_string()), Integer(0x10000)] {
+            let reason = Array(vec![Integer(0), Array(vec![Integer(6), version])]);
+            let message = [Integer(2), reason];
+            assert!(Message::from_iter(Values::from_vec(&message)).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_client_reports_refusal() {
+        let (mut connection, endpoint) = Connection::test_unix_pair().unwrap();
+        let mut channel = endpoint.channel(0x8000);
+
+        let reason = RefuseReason::VersionMismatch(vec![11, 12]);
+        tokio::join!(
+            async {
+                let mut client = builder().node_to_node().client(&mut connection).unwrap();
+                assert_eq!(
+                    client.negotiate().await,
+                    Err(HandshakeError::Refused(reason.clone()))
+                );
+                assert!(client.send().is_err());
+                assert!(client.recv(Message::Refuse(reason.clone())).is_err());
+            },
+            async {
+                channel.recv().await.unwrap();
+                let response = Message::Refuse(reason.clone());
+                channel.send(&response.to_bytes()).await.unwrap();
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_client_reports_magic_mismatch() {
+        let (mut connection, endpoint) = Connection::test_unix_pair().unwrap();
+        let mut channel = endpoint.channel(0x8000);
+
+        tokio::join!(
+            async {
+                let mut client = builder()
+                    .node_to_node()
+                    .network_magic(764824073)
+                    .client(&mut connection)
+                    .unwrap();
+                assert_eq!(
+                    client.negotiate().await,
+                    Err(HandshakeError::NetworkMagicMismatch {
+                        expected: 764824073,
+                        received: 1,
+                    })
+                );
+                assert_eq!(client.version(), None);
+            },
+            async {
+                channel.recv().await.unwrap();
+                channel.send(&confirm(1)).await.unwrap();
+            },
+        );
+    }
 }
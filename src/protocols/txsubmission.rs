@@ -108,12 +108,12 @@ impl<'a> Protocol<'a> for TxSubmission<'a> {
     }
 
     fn agency(&self) -> Agency {
-        return match self.state {
+        match self.state {
             State::Idle => Agency::None,
             State::TxIdsBlocking => Agency::None,
             State::TxIdsNonBlocking => Agency::None,
             State::Done => Agency::None,
-        };
+        }
     }
 
     fn state(&self) -> Self::State {
@@ -121,7 +121,7 @@ impl<'a> Protocol<'a> for TxSubmission<'a> {
     }
 
     fn send(&mut self) -> Result<Self::Message, Error> {
-        return match self.state {
+        match self.state {
             State::TxIdsBlocking => {
                 debug!("TxSubmission::State::TxIdsBlocking");
                 // Server will wait on us forever. Just move to Done state.
@@ -136,7 +136,7 @@ impl<'a> Protocol<'a> for TxSubmission<'a> {
                 Ok(payload)
             }
             state => Err(format!("Unexpected state: {:?}", state)),
-        };
+        }
     }
 
     fn recv(&mut self, message: Self::Message) -> Result<(), Error> {
@@ -173,7 +173,7 @@ impl<'a> Protocol<'a> for TxSubmission<'a> {
         Ok(())
     }
 
-    fn channel<'b>(&'b mut self) -> &mut Channel<'a>
+    fn channel<'b>(&'b mut self) -> &'b mut Channel<'a>
     where
         'a: 'b,
     {
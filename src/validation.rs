@@ -0,0 +1,357 @@
+//
+// © 2022 PERLUR Group
+//
+// SPDX-License-Identifier: MPL-2.0
+//
+
+mod vrf;
+
+use crate::{
+    model::{
+        BlockHeader,
+        Hash,
+    },
+    protocols::PRAOS_ERA,
+    Error,
+};
+use ed25519_dalek::{
+    Signature,
+    Verifier,
+    VerifyingKey,
+};
+use kes_summed_ed25519::{
+    kes::Sum6KesSig,
+    traits::KesSig,
+    PublicKey as KesPublicKey,
+};
+
+// Nonce and leader VRF seeds, `mkNonceFromNumber` of 0 and 1.
+const SEED_ETA: u64 = 0;
+const SEED_L: u64 = 1;
+
+pub fn builder() -> Builder {
+    Default::default()
+}
+
+pub struct Builder {
+    epoch_nonce: Option<Vec<u8>>,
+    slots_per_kes_period: u64,
+    max_kes_evolutions: u64,
+}
+
+impl Default for Builder {
+    // Mainnet parameters and the neutral nonce.
+    fn default() -> Self {
+        Builder {
+            epoch_nonce: None,
+            slots_per_kes_period: 129600,
+            max_kes_evolutions: 62,
+        }
+    }
+}
+
+impl Builder {
+    pub fn epoch_nonce(&mut self, nonce: Vec<u8>) -> &mut Self {
+        self.epoch_nonce = Some(nonce);
+        self
+    }
+
+    pub fn slots_per_kes_period(&mut self, slots: u64) -> &mut Self {
+        self.slots_per_kes_period = slots;
+        self
+    }
+
+    pub fn max_kes_evolutions(&mut self, evolutions: u64) -> &mut Self {
+        self.max_kes_evolutions = evolutions;
+        self
+    }
+
+    pub fn build(&self) -> Result<Validator, Error> {
+        if self.slots_per_kes_period == 0 {
+            return Err("Slots per KES period must not be zero.".to_string());
+        }
+        if let Some(nonce) = &self.epoch_nonce {
+            if nonce.len() != 32 {
+                return Err(format!("Bad epoch nonce length: {}", nonce.len()));
+            }
+        }
+        Ok(Validator {
+            epoch_nonce: self.epoch_nonce.clone(),
+            slots_per_kes_period: self.slots_per_kes_period,
+            max_kes_evolutions: self.max_kes_evolutions,
+        })
+    }
+}
+
+pub struct Validator {
+    epoch_nonce: Option<Vec<u8>>,
+    slots_per_kes_period: u64,
+    max_kes_evolutions: u64,
+}
+
+impl Validator {
+    pub fn validate(&self, header: &BlockHeader) -> Result<(), Error> {
+        self.validate_hash(header)?;
+        self.validate_opcert(header)?;
+        self.validate_kes_signature(header)?;
+        self.validate_vrf(header)?;
+        Ok(())
+    }
+
+    pub fn validate_hash(&self, header: &BlockHeader) -> Result<(), Error> {
+        match Hash::blake2b(&header.bytes) == header.hash {
+            true => Ok(()),
+            false => Err("Header hash mismatch.".to_string()),
+        }
+    }
+
+    pub fn validate_opcert(&self, header: &BlockHeader) -> Result<(), Error> {
+        let opcert = &header.operational_cert;
+        let cold_key = VerifyingKey::try_from(&*header.node_vkey)
+            .map_err(|e| format!("Bad cold key: {}", e))?;
+        let sigma = Signature::from_slice(&opcert.sigma)
+            .map_err(|e| format!("Bad opcert signature: {}", e))?;
+        let mut message = opcert.hot_vkey.to_vec();
+        message.extend(opcert.sequence_number.to_be_bytes());
+        message.extend(opcert.kes_period.to_be_bytes());
+        cold_key
+            .verify(&message, &sigma)
+            .map_err(|_| "Opcert signature verification failed.".to_string())
+    }
+
+    pub fn validate_kes_signature(&self, header: &BlockHeader) -> Result<(), Error> {
+        let opcert = &header.operational_cert;
+        let current_period = header.slot_number as u64 / self.slots_per_kes_period;
+        if current_period < opcert.kes_period {
+            return Err(format!(
+                "Opcert KES period {} is in the future, current period is {}.",
+                opcert.kes_period, current_period,
+            ));
+        }
+        let period = current_period - opcert.kes_period;
+        if period >= self.max_kes_evolutions {
+            return Err(format!(
+                "Opcert KES period {} expired, current period is {}.",
+                opcert.kes_period, current_period,
+            ));
+        }
+        let hot_key = KesPublicKey::from_bytes(&opcert.hot_vkey)
+            .map_err(|e| format!("Bad KES key: {:?}", e))?;
+        Sum6KesSig::from_bytes(&header.kes_signature)
+            .map_err(|e| format!("Bad KES signature: {:?}", e))?
+            .verify(period as u32, &hot_key, &header.body_bytes)
+            .map_err(|_| "KES signature verification failed.".to_string())
+    }
+
+    pub fn validate_vrf(&self, header: &BlockHeader) -> Result<(), Error> {
+        let input = self.vrf_input(header.slot_number as u64);
+        let checks = match header.era >= PRAOS_ERA {
+            // Praos proves a single result, decoded into the leader fields,
+            // and derives the nonce and the leader value from its output.
+            true => vec![("result", input, &header.leader_vrf_0, &header.leader_vrf_1)],
+            false => vec![
+                (
+                    "nonce",
+                    seeded(&input, SEED_ETA),
+                    &header.eta_vrf_0,
+                    &header.eta_vrf_1,
+                ),
+                (
+                    "leader",
+                    seeded(&input, SEED_L),
+                    &header.leader_vrf_0,
+                    &header.leader_vrf_1,
+                ),
+            ],
+        };
+        for (name, alpha, output, proof) in checks {
+            if vrf::verify(&header.node_vrf_vkey, proof, &alpha)? != **output {
+                return Err(format!("VRF {} output mismatch.", name));
+            }
+        }
+        Ok(())
+    }
+
+    // Hash of the slot and the epoch nonce, `mkInputVRF` in Praos.
+    fn vrf_input(&self, slot: u64) -> [u8; 32] {
+        let mut data = slot.to_be_bytes().to_vec();
+        if let Some(nonce) = &self.epoch_nonce {
+            data.extend(nonce);
+        }
+        let mut input = [0u8; 32];
+        input.copy_from_slice(&Hash::<32>::blake2b(&data));
+        input
+    }
+}
+
+// Seed as computed by `mkSeed` in the Shelley protocol.
+fn seeded(input: &[u8; 32], seed: u64) -> [u8; 32] {
+    let seed = Hash::<32>::blake2b(&seed.to_be_bytes());
+    let mut alpha = [0u8; 32];
+    for (i, byte) in alpha.iter_mut().enumerate() {
+        *byte = input[i] ^ seed[i];
+    }
+    alpha
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        model::{
+            mock_header,
+            OperationalCert,
+        },
+        protocols::WrappedBlockHeader,
+    };
+    use ed25519_dalek::{
+        Signer,
+        SigningKey,
+    };
+    use kes_summed_ed25519::{
+        kes::Sum6Kes,
+        traits::KesSk,
+    };
+
+    const COLD_SEED: [u8; 32] = [1; 32];
+    const VRF_SEED: [u8; 32] = [2; 32];
+    const KES_SEED: [u8; 32] = [3; 32];
+    const EPOCH_NONCE: [u8; 32] = [4; 32];
+    const SLOTS_PER_KES_PERIOD: u64 = 100;
+
+    fn validator() -> Validator {
+        builder()
+            .epoch_nonce(EPOCH_NONCE.to_vec())
+            .slots_per_kes_period(SLOTS_PER_KES_PERIOD)
+            .build()
+            .unwrap()
+    }
+
+    fn signed_header(era: u64, slot_number: i64, kes_period: u64) -> BlockHeader {
+        let slot = slot_number as u64;
+        let cold_key = SigningKey::from_bytes(&COLD_SEED);
+        let vrf_vkey = Hash::new(SigningKey::from_bytes(&VRF_SEED).verifying_key().to_bytes());
+        let mut kes_buffer = [0u8; Sum6Kes::SIZE + 4];
+        let mut kes_seed = KES_SEED;
+        let (mut kes_key, kes_vkey) = Sum6Kes::keygen(&mut kes_buffer, &mut kes_seed);
+
+        let vrf = |alpha: [u8; 32]| {
+            let proof = vrf::prove(&VRF_SEED, &alpha).unwrap();
+            let output = vrf::verify(&vrf_vkey, &proof, &alpha).unwrap();
+            (output.to_vec(), proof.to_vec())
+        };
+        let input = validator().vrf_input(slot);
+        let ((eta_vrf_0, eta_vrf_1), (leader_vrf_0, leader_vrf_1)) = match era >= PRAOS_ERA {
+            // Praos headers carry one result, decoded into both pairs.
+            true => (vrf(input), vrf(input)),
+            false => (vrf(seeded(&input, SEED_ETA)), vrf(seeded(&input, SEED_L))),
+        };
+
+        let operational_cert = {
+            let hot_vkey = Hash::try_from(kes_vkey.as_bytes()).unwrap();
+            let sequence_number = 3;
+            let mut message = hot_vkey.to_vec();
+            message.extend(u64::to_be_bytes(sequence_number));
+            message.extend(kes_period.to_be_bytes());
+            OperationalCert {
+                hot_vkey,
+                sequence_number,
+                kes_period,
+                sigma: cold_key.sign(&message).to_bytes().to_vec(),
+            }
+        };
+
+        let mut header = BlockHeader {
+            node_vkey: Hash::new(cold_key.verifying_key().to_bytes()),
+            node_vrf_vkey: vrf_vkey,
+            eta_vrf_0,
+            eta_vrf_1,
+            leader_vrf_0,
+            leader_vrf_1,
+            operational_cert,
+            era,
+            ..mock_header(1, slot_number, Hash::new([0; 32]))
+        };
+        // Sign the encoded header body at the relative KES period.
+        let wrapped: WrappedBlockHeader = header.clone().try_into().unwrap();
+        let unsigned: BlockHeader = wrapped.try_into().unwrap();
+        for _ in kes_period..slot / SLOTS_PER_KES_PERIOD {
+            kes_key.update().unwrap();
+        }
+        header.kes_signature = kes_key.sign(&unsigned.body_bytes).to_bytes().to_vec();
+        let wrapped: WrappedBlockHeader = header.try_into().unwrap();
+        wrapped.try_into().unwrap()
+    }
+
+    #[test]
+    fn validate_works() {
+        for era in [1, PRAOS_ERA] {
+            let header = signed_header(era, 1234, 10);
+            assert_eq!(validator().validate(&header), Ok(()));
+        }
+    }
+
+    #[test]
+    fn validate_vrf_follows_era() {
+        let validator = validator();
+        let tpraos = signed_header(1, 1234, 10);
+        let praos = signed_header(PRAOS_ERA, 1234, 10);
+        assert_ne!(tpraos.leader_vrf_1, praos.leader_vrf_1);
+        // Praos proves the unseeded input.
+        let mut bad = tpraos.clone();
+        bad.era = PRAOS_ERA;
+        assert!(validator.validate_vrf(&bad).is_err());
+        let mut bad = praos.clone();
+        bad.era = 1;
+        assert!(validator.validate_vrf(&bad).is_err());
+        let mut bad = praos;
+        bad.leader_vrf_0[0] ^= 1;
+        assert!(validator.validate_vrf(&bad).is_err());
+    }
+
+    #[test]
+    fn validate_detects_tampering() {
+        let validator = validator();
+        let header = signed_header(1, 1234, 10);
+
+        let mut bad = header.clone();
+        bad.hash = Hash::new([0; 32]);
+        assert!(validator.validate_hash(&bad).is_err());
+
+        let mut bad = header.clone();
+        bad.operational_cert.sequence_number += 1;
+        assert!(validator.validate_opcert(&bad).is_err());
+
+        let mut bad = header.clone();
+        bad.body_bytes[3] ^= 1;
+        assert!(validator.validate_kes_signature(&bad).is_err());
+
+        let mut bad = header.clone();
+        bad.slot_number += SLOTS_PER_KES_PERIOD as i64;
+        assert!(validator.validate_kes_signature(&bad).is_err());
+        assert!(validator.validate_vrf(&bad).is_err());
+
+        let mut bad = header.clone();
+        bad.leader_vrf_0[0] ^= 1;
+        assert!(validator.validate_vrf(&bad).is_err());
+
+        let other_nonce = builder()
+            .epoch_nonce(vec![5; 32])
+            .slots_per_kes_period(SLOTS_PER_KES_PERIOD)
+            .build()
+            .unwrap();
+        assert!(other_nonce.validate_vrf(&header).is_err());
+    }
+
+    #[test]
+    fn validate_checks_kes_window() {
+        let validator = builder()
+            .epoch_nonce(EPOCH_NONCE.to_vec())
+            .slots_per_kes_period(SLOTS_PER_KES_PERIOD)
+            .max_kes_evolutions(5)
+            .build()
+            .unwrap();
+        assert_eq!(validator.validate(&signed_header(1, 1234, 10)), Ok(()));
+        assert!(validator.validate(&signed_header(1, 1634, 10)).is_err());
+    }
+}
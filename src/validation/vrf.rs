@@ -0,0 +1,220 @@
+//
+// © 2022 PERLUR Group
+//
+// SPDX-License-Identifier: MPL-2.0
+//
+
+//
+// ECVRF-ED25519-SHA512-Elligator2 as specified in draft-irtf-cfrg-vrf-03
+// and used by the Cardano node.
+//
+
+use crate::Error;
+use curve25519_dalek::{
+    edwards::{
+        CompressedEdwardsY,
+        EdwardsPoint,
+    },
+    montgomery::MontgomeryPoint,
+    scalar::Scalar,
+};
+use num_bigint::BigUint;
+use sha2::{
+    Digest,
+    Sha512,
+};
+
+const SUITE: u8 = 0x04;
+const ONE: u8 = 0x01;
+const TWO: u8 = 0x02;
+const THREE: u8 = 0x03;
+
+pub const PROOF_SIZE: usize = 80;
+pub const OUTPUT_SIZE: usize = 64;
+
+// Montgomery curve25519 coefficient A.
+const CURVE25519_A: u32 = 486662;
+
+fn field_prime() -> BigUint {
+    (BigUint::from(1u8) << 255) - BigUint::from(19u8)
+}
+
+fn field_from_bytes(bytes: &[u8; 32]) -> BigUint {
+    BigUint::from_bytes_le(bytes) % field_prime()
+}
+
+fn field_to_bytes(value: &BigUint) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    let le = value.to_bytes_le();
+    bytes[..le.len()].copy_from_slice(&le);
+    bytes
+}
+
+//
+// Elligator2 map as implemented by libsodium's `ge25519_from_uniform` with the
+// sign bit cleared, followed by cofactor clearing.
+//
+fn elligator2(r_bytes: &[u8; 32]) -> Result<EdwardsPoint, Error> {
+    let p = field_prime();
+    let a = BigUint::from(CURVE25519_A);
+    let r = field_from_bytes(r_bytes);
+
+    // x = -A / (1 + 2 r^2)
+    let denominator = (BigUint::from(2u8) * &r * &r + 1u8) % &p;
+    let inverse = denominator.modpow(&(&p - 2u8), &p);
+    let x = (&p - (&a * inverse) % &p) % &p;
+
+    // e = x^3 + A x^2 + x
+    let x2 = (&x * &x) % &p;
+    let e = (&x2 * &x + &a * &x2 + &x) % &p;
+    let chi = e.modpow(&((&p - 1u8) >> 1), &p);
+
+    let u = match chi == &p - 1u8 {
+        true => (&p + &p - &x - &a) % &p,
+        false => x,
+    };
+    MontgomeryPoint(field_to_bytes(&u))
+        .to_edwards(0)
+        .map(|point| point.mul_by_cofactor())
+        .ok_or_else(|| "Elligator2 map failed.".to_string())
+}
+
+fn hash_to_curve(public_key: &[u8; 32], alpha: &[u8]) -> Result<EdwardsPoint, Error> {
+    let digest = Sha512::new()
+        .chain_update([SUITE, ONE])
+        .chain_update(public_key)
+        .chain_update(alpha)
+        .finalize();
+    let mut r_bytes = [0u8; 32];
+    r_bytes.copy_from_slice(&digest[..32]);
+    r_bytes[31] &= 0x7f;
+    elligator2(&r_bytes)
+}
+
+fn hash_points(points: &[&EdwardsPoint]) -> [u8; 16] {
+    let mut hasher = Sha512::new().chain_update([SUITE, TWO]);
+    for point in points {
+        hasher.update(point.compress().as_bytes());
+    }
+    let mut challenge = [0u8; 16];
+    challenge.copy_from_slice(&hasher.finalize()[..16]);
+    challenge
+}
+
+fn decompress(bytes: &[u8]) -> Result<EdwardsPoint, Error> {
+    CompressedEdwardsY::from_slice(bytes)
+        .ok()
+        .and_then(|point| point.decompress())
+        .ok_or_else(|| "Invalid curve point.".to_string())
+}
+
+fn proof_to_hash(gamma: &EdwardsPoint) -> [u8; OUTPUT_SIZE] {
+    let digest = Sha512::new()
+        .chain_update([SUITE, THREE])
+        .chain_update(gamma.mul_by_cofactor().compress().as_bytes())
+        .finalize();
+    let mut output = [0u8; OUTPUT_SIZE];
+    output.copy_from_slice(&digest);
+    output
+}
+
+//
+// Verify `proof` of `alpha` under `public_key` and return the VRF output.
+//
+pub fn verify(public_key: &[u8], proof: &[u8], alpha: &[u8]) -> Result<[u8; OUTPUT_SIZE], Error> {
+    let public_key: [u8; 32] = public_key
+        .try_into()
+        .map_err(|_| format!("Bad VRF key length: {}", public_key.len()))?;
+    if proof.len() != PROOF_SIZE {
+        return Err(format!("Bad VRF proof length: {}", proof.len()));
+    }
+    let y = decompress(&public_key)?;
+    if y.is_small_order() {
+        return Err("VRF key has small order.".to_string());
+    }
+    let gamma = decompress(&proof[0..32])?;
+    let mut c_bytes = [0u8; 32];
+    c_bytes[..16].copy_from_slice(&proof[32..48]);
+    let c = Scalar::from_bytes_mod_order(c_bytes);
+    let s: Option<Scalar> = Scalar::from_canonical_bytes(proof[48..80].try_into().unwrap()).into();
+    let s = s.ok_or("Non-canonical VRF proof scalar.")?;
+
+    let h = hash_to_curve(&public_key, alpha)?;
+    let u = EdwardsPoint::vartime_double_scalar_mul_basepoint(&-c, &y, &s);
+    let v = s * h - c * gamma;
+    match hash_points(&[&h, &gamma, &u, &v]) == proof[32..48] {
+        true => Ok(proof_to_hash(&gamma)),
+        false => Err("VRF proof verification failed.".to_string()),
+    }
+}
+
+//
+// Produce a proof of `alpha` using the 32-byte secret key seed, only used to
+// generate mock headers.
+//
+#[cfg(test)]
+pub fn prove(secret_key: &[u8; 32], alpha: &[u8]) -> Result<[u8; PROOF_SIZE], Error> {
+    let az = Sha512::digest(secret_key);
+    let mut x_bytes = [0u8; 32];
+    x_bytes.copy_from_slice(&az[..32]);
+    x_bytes[0] &= 248;
+    x_bytes[31] &= 127;
+    x_bytes[31] |= 64;
+    let x = Scalar::from_bytes_mod_order(x_bytes);
+    let public_key = EdwardsPoint::mul_base(&x).compress().to_bytes();
+
+    let h = hash_to_curve(&public_key, alpha)?;
+    let gamma = x * h;
+    let k_digest = Sha512::new()
+        .chain_update(&az[32..])
+        .chain_update(h.compress().as_bytes())
+        .finalize();
+    let k = Scalar::from_bytes_mod_order_wide(&k_digest.into());
+    let challenge = hash_points(&[&h, &gamma, &EdwardsPoint::mul_base(&k), &(k * h)]);
+    let mut c_bytes = [0u8; 32];
+    c_bytes[..16].copy_from_slice(&challenge);
+    let s = k + Scalar::from_bytes_mod_order(c_bytes) * x;
+
+    let mut proof = [0u8; PROOF_SIZE];
+    proof[0..32].copy_from_slice(gamma.compress().as_bytes());
+    proof[32..48].copy_from_slice(&challenge);
+    proof[48..80].copy_from_slice(s.as_bytes());
+    Ok(proof)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // draft-irtf-cfrg-vrf-03, ECVRF-ED25519-SHA512-Elligator2, example 10.
+    const PUBLIC_KEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    const PROOF: &str = "b6b4699f87d56126c9117a7da55bd0085246f4c56dbc95d20172612e9d38e8d7\
+                         ca65e573a126ed88d4e30a46f80a666854d675cf3ba81de0de043c3774f06156\
+                         0f55edc256a787afe701677c0f602900";
+    const OUTPUT: &str = "5b49b554d05c0cd5a5325376b3387de59d924fd1e13ded44648ab33c21349a60\
+                          3f25b84ec5ed887995b33da5e3bfcb87cd2f64521c4c62cf825cffabbe5d31cc";
+
+    #[test]
+    fn verify_works() {
+        let public_key = hex::decode(PUBLIC_KEY).unwrap();
+        let proof = hex::decode(PROOF).unwrap();
+        assert_eq!(
+            verify(&public_key, &proof, b"").map(|output| output.to_vec()),
+            Ok(hex::decode(OUTPUT).unwrap()),
+        );
+        assert!(verify(&public_key, &proof, b"other").is_err());
+    }
+
+    #[test]
+    fn prove_works() {
+        let secret_key: [u8; 32] =
+            hex::decode("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60")
+                .unwrap()
+                .try_into()
+                .unwrap();
+        assert_eq!(
+            prove(&secret_key, b"").unwrap().to_vec(),
+            hex::decode(PROOF).unwrap()
+        );
+    }
+}